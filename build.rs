@@ -1,6 +1,14 @@
+use std::process::Command;
+
 use tonic_build::configure;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Reported as the `gl-rust` token of `x-goog-api-client`, which names the language version.
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let version = String::from_utf8(Command::new(rustc).arg("--version").output()?.stdout)?;
+    let version = version.split_whitespace().nth(1).unwrap_or("unknown");
+    println!("cargo:rustc-env=RUSTC_VERSION={}", version);
+
     configure()
         .build_server(false)
         .out_dir("./src/protodefs")
@@ -10,6 +18,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 "googleapis-src/google/api/auth.proto",
                 "googleapis-src/google/datastore/v1/datastore.proto",
                 "googleapis-src/google/api/auth.proto",
+                "googleapis-src/google/rpc/error_details.proto",
             ],
             &["googleapis-src"],
         )?;
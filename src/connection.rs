@@ -1,47 +1,136 @@
-use std::collections::HashMap;
-use std::error::Error;
-use std::path::PathBuf;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use async_trait::async_trait;
+use http::header::HeaderValue;
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 use serde::{Deserialize, Serialize};
-use tonic::transport::{Channel, ClientTlsConfig, Endpoint, Identity, Uri};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Uri};
 
-#[derive(Serialize)]
+use crate::error::Error;
+
+mod auth_service;
+mod authorized_user;
+mod channel_pool;
+mod default_credentials;
+mod external_account;
+mod iam;
+mod impersonated;
+mod metadata;
+mod self_signed_jwt;
+mod token_cache;
+mod token_source;
+mod user_agent;
+
+pub use auth_service::AuthService;
+pub use authorized_user::AuthorizedUser;
+pub use channel_pool::{ChannelPool, Lease, PoolOptions, PooledChannel};
+pub(crate) use default_credentials::project_id_from_env;
+pub use default_credentials::ApplicationCredentials;
+pub use external_account::ExternalAccount;
+pub use impersonated::ImpersonatedServiceAccount;
+pub use metadata::MetadataServer;
+pub use self_signed_jwt::SelfSignedJwt;
+pub use token_cache::{RefreshOptions, TokenCache};
+pub use token_source::{from_fn, FnTokenSource, StaticToken, TokenSource};
+pub use user_agent::UserAgent;
+
+// Tokens are refreshed this long before they expire so requests in flight never carry a
+// token that lapses on the way to the server.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+// Allowance for the local clock disagreeing with Google's about when a token expires.
+const CLOCK_SKEW_ALLOWANCE: Duration = Duration::from_secs(30);
+
+const JWT_LIFETIME: Duration = Duration::from_secs(3600);
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+
+fn default_token_uri() -> String {
+    DEFAULT_TOKEN_URI.to_owned()
+}
+
+#[derive(Serialize, Deserialize)]
 struct Claim {
     iss: String,
-    scope: String,
-    aud: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    aud: String,
     exp: usize,
     iat: usize,
 }
 
 impl Claim {
-    fn new<S: AsRef<str>>(iss: String, scope: S) -> Self {
-        let now = {
-            let systime = SystemTime::now();
-            systime.duration_since(UNIX_EPOCH).unwrap().as_secs() as usize
-        };
+    fn new<S: AsRef<str>>(iss: String, scope: S, token_uri: &str) -> Self {
+        let now = unix_now();
         Claim {
             iss,
-            scope: scope.as_ref().to_owned(),
-            aud: "https://oauth2.googleapis.com/token",
-            exp: now + 3600,
+            sub: None,
+            scope: Some(scope.as_ref().to_owned()),
+            aud: token_uri.to_owned(),
+            exp: now + JWT_LIFETIME.as_secs() as usize,
             iat: now,
         }
     }
+
+    /// A claim the API itself accepts as a bearer token, addressed to the service endpoint.
+    fn self_signed<S: AsRef<str>>(email: String, audience: S) -> Self {
+        let now = unix_now();
+        Claim {
+            iss: email.clone(),
+            sub: Some(email),
+            scope: None,
+            aud: audience.as_ref().to_owned(),
+            exp: now + JWT_LIFETIME.as_secs() as usize,
+            iat: now,
+        }
+    }
+}
+
+fn unix_now() -> usize {
+    let systime = SystemTime::now();
+    systime.duration_since(UNIX_EPOCH).unwrap().as_secs() as usize
 }
 
 #[derive(Deserialize, Clone)]
 pub struct Credentials {
-    client_x509_cert_url: String,
-    // auth_provider_x509_cert_url: String,
     client_email: String,
     private_key_id: String,
     pub project_id: String,
     private_key: String,
-    oauth_token: Option<String>,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+    #[serde(default)]
+    quota_project_id: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessToken {
+    pub token: String,
+    /// `None` for tokens with no known expiry, such as pre-minted static tokens.
+    pub expires_at: Option<SystemTime>,
 }
+
+impl AccessToken {
+    pub fn new(token: String, expires_in: Duration) -> Self {
+        AccessToken {
+            token,
+            expires_at: Some(SystemTime::now() + expires_in),
+        }
+    }
+
+    /// Whether the token can still be sent, leaving room for the refresh margin and clock skew.
+    pub fn is_fresh(&self) -> bool {
+        let expires_at = match self.expires_at {
+            Some(expires_at) => expires_at,
+            None => return true,
+        };
+        match expires_at.checked_sub(TOKEN_REFRESH_MARGIN + CLOCK_SKEW_ALLOWANCE) {
+            Some(refresh_at) => SystemTime::now() < refresh_at,
+            None => false,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct OauthResponse {
     access_token: String,
@@ -49,112 +138,281 @@ struct OauthResponse {
     token_type: String,
     expires_in: usize,
 }
-impl Credentials {
-    pub fn from_json(json_str: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(json_str)
-    }
-
-    pub fn auto_acquire() -> Result<Self, Box<dyn Error>> {
-        let path = std::env::var("GOOGLE_APPLICATION_CREDENTIALS").map(|env| PathBuf::from(env))?;
-        Ok(std::fs::read_to_string(&path).map(|data| {
-            Credentials::from_json(&data).expect("Unable to parse credentials in environment")
-        })?)
-    }
-
-    pub async fn get_oauth_token(&mut self, scope: &str) -> Result<String, Box<dyn Error>> {
-        match &self.oauth_token {
-            Some(x) => Ok(x.to_owned()),
-            None => {
-                self.request_oauth_token(scope).await?;
-                if let Some(token) = &self.oauth_token {
-                    Ok(token.clone())
-                } else {
-                    Err("Unable to get things".into())
-                }
-            }
+
+impl OauthResponse {
+    fn into_access_token(self) -> AccessToken {
+        AccessToken::new(
+            self.access_token,
+            Duration::from_secs(self.expires_in as u64),
+        )
+    }
+
+    /// Reads a token endpoint response, turning OAuth error bodies into readable errors.
+    async fn read(res: reqwest::Response) -> Result<AccessToken, Error> {
+        let status = res.status();
+        if status.is_success() {
+            let body = res.json::<OauthResponse>().await?;
+            return Ok(body.into_access_token());
         }
+        let body = res.text().await?;
+        Err(Error::token(describe_oauth_error(status.as_u16(), &body)))
+    }
+}
+
+#[derive(Deserialize)]
+struct OauthError {
+    error: String,
+    error_description: Option<String>,
+}
+
+fn describe_oauth_error(status: u16, body: &str) -> String {
+    match serde_json::from_str::<OauthError>(body) {
+        Ok(OauthError {
+            error,
+            error_description: Some(description),
+        }) => format!(
+            "Token endpoint returned {} ({}): {}",
+            error, status, description
+        ),
+        Ok(OauthError { error, .. }) => format!("Token endpoint returned {} ({})", error, status),
+        Err(_) => format!("Token endpoint returned HTTP {}: {}", status, body),
+    }
+}
+
+impl Credentials {
+    pub fn from_json(json_str: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(json_str)?)
+    }
+
+    /// Exchanges JWTs at `token_uri` instead of the endpoint named in the key file.
+    pub fn with_token_uri<S: Into<String>>(mut self, token_uri: S) -> Self {
+        self.token_uri = token_uri.into();
+        self
+    }
+
+    /// Finds credentials through Application Default Credentials discovery. See
+    /// `ApplicationCredentials::find`.
+    pub async fn auto_acquire() -> Result<ApplicationCredentials, Error> {
+        ApplicationCredentials::find().await
     }
 
-    async fn request_oauth_token(&mut self, scope: &str) -> Result<(), Box<dyn Error>> {
+    async fn exchange_jwt(&self, scope: &str) -> Result<AccessToken, Error> {
         let request = self.generate_jwt_request(scope)?;
         let client = reqwest::Client::new();
         let params = [
             ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
             ("assertion", &request),
         ];
-        let res: reqwest::Response = client
-            .post("https://oauth2.googleapis.com/token")
-            .form(&params)
-            .send()
-            .await?;
-        let body: OauthResponse = res.json::<OauthResponse>().await?;
-        self.oauth_token = Some(body.access_token);
-        Ok(())
-    }
-
-    fn generate_jwt_request(&self, scope: &str) -> Result<String, Box<dyn Error>> {
-        let req = Claim::new(self.client_email.to_owned(), scope);
+        let res: reqwest::Response = client.post(&self.token_uri).form(&params).send().await?;
+        OauthResponse::read(res).await
+    }
+
+    /// Authorizes with self-signed JWTs for `audience` instead of exchanging them for OAuth
+    /// tokens. See `SelfSignedJwt`.
+    pub fn self_signed_jwt<S: Into<String>>(self, audience: S) -> SelfSignedJwt {
+        SelfSignedJwt::new(self, audience)
+    }
+
+    fn generate_jwt_request(&self, scope: &str) -> Result<String, Error> {
+        let req = Claim::new(self.client_email.to_owned(), scope, &self.token_uri);
+        self.sign(&req, None)
+    }
+
+    fn sign(&self, claim: &Claim, kid: Option<String>) -> Result<String, Error> {
         let header = Header {
             alg: Algorithm::RS256,
             cty: None,
             jku: None,
-            kid: None,
+            kid,
             x5u: None,
             typ: Some("JWT".to_owned()),
             x5t: None,
         };
 
-        let output = encode(
-            &header,
-            &req,
-            &EncodingKey::from_rsa_pem(self.private_key.as_bytes())?,
-        );
-        output.map_err(|e| e.into())
+        let key = EncodingKey::from_rsa_pem(self.private_key.as_bytes())
+            .map_err(|e| Error::credentials("the service account private key", e))?;
+        encode(&header, claim, &key)
+            .map_err(|e| Error::credentials("the service account private key", e))
     }
 }
 
+#[async_trait]
+impl TokenSource for Credentials {
+    async fn fetch_token(&self, scope: &str) -> Result<AccessToken, Error> {
+        self.exchange_jwt(scope).await
+    }
+
+    fn quota_project_id(&self) -> Option<&str> {
+        self.quota_project_id.as_deref()
+    }
+}
+
+/// Where and how to reach a gRPC service. Connections use TLS verified against the system
+/// roots; authorization travels separately as bearer tokens in request metadata.
+#[derive(Clone)]
 pub struct GrpcEndpoint {
-    inner: Endpoint,
+    domain_name: String,
+    address: Option<String>,
+    tls: bool,
+    identity: Option<Identity>,
+    ca_certificate: Option<Certificate>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+    tcp_nodelay: bool,
+    concurrency_limit: Option<usize>,
+    user_agent: Option<HeaderValue>,
 }
 
 impl GrpcEndpoint {
-    pub async fn new(credentials: &Credentials, domain_name: &str) -> Result<Self, Box<dyn Error>> {
-        let identity = {
-            let re: HashMap<String, String> = reqwest::get(&credentials.client_x509_cert_url)
-                .await?
-                .json::<HashMap<String, String>>()
-                .await?;
-            let key = re.get(&credentials.private_key_id).expect("No private key");
-
-            Identity::from_pem(key.as_bytes(), &credentials.private_key.as_bytes())
-        };
+    pub fn new(domain_name: &str) -> Self {
+        GrpcEndpoint {
+            domain_name: domain_name.to_owned(),
+            address: None,
+            tls: true,
+            identity: None,
+            ca_certificate: None,
+            connect_timeout: None,
+            timeout: None,
+            tcp_keepalive: None,
+            tcp_nodelay: false,
+            concurrency_limit: None,
+            user_agent: None,
+        }
+    }
 
-        let tls_config = ClientTlsConfig::new()
-            .identity(identity)
-            .domain_name(domain_name);
+    /// Plaintext HTTP/2 to `host:port`, for local emulators.
+    pub fn insecure(authority: &str) -> Self {
+        GrpcEndpoint {
+            tls: false,
+            ..GrpcEndpoint::new(authority)
+        }
+    }
+
+    /// Dials `host[:port]` instead of the domain name, e.g. a regional or Private Service
+    /// Connect endpoint. The certificate is still verified against the domain name.
+    pub fn address(mut self, authority: &str) -> Self {
+        self.address = Some(authority.to_owned());
+        self
+    }
+
+    /// Presents a client certificate, for deployments that require mutual TLS such as mTLS
+    /// endpoints or Private Service Connect.
+    pub fn mutual_tls(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Trusts an additional certificate authority, e.g. for a private endpoint.
+    pub fn ca_certificate(mut self, certificate: Certificate) -> Self {
+        self.ca_certificate = Some(certificate);
+        self
+    }
+
+    /// Gives up on establishing the connection after `timeout`. Only `GrpcEndpoint::connect`
+    /// can enforce this; a tonic `Endpoint` converted from this one has no connect timeout.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails each request that has not completed within `timeout`. `Firestore` reports this as
+    /// DEADLINE_EXCEEDED; a raw client gets the status tonic gives any other transport error,
+    /// UNKNOWN.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Enables TCP keepalive on the socket, probing after `idle` without traffic. This is not
+    /// HTTP/2 PING keepalive, which the transport does not support.
+    pub fn tcp_keepalive(mut self, idle: Duration) -> Self {
+        self.tcp_keepalive = Some(idle);
+        self
+    }
+
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.tcp_nodelay = enabled;
+        self
+    }
+
+    /// Caps the number of requests in flight on the connection.
+    pub fn concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Sent as the `user-agent` header of every call made through `Firestore`. Raw clients need
+    /// their channel wrapped in a `UserAgent` instead.
+    pub fn user_agent(mut self, user_agent: HeaderValue) -> Self {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    /// Opens the connection, honouring `connect_timeout`.
+    pub async fn connect(self) -> Result<Channel, Error> {
+        let connect_timeout = self.connect_timeout;
+        let endpoint = self.endpoint();
+        match connect_timeout {
+            Some(limit) => tokio::time::timeout(limit, endpoint.connect())
+                .await
+                .map_err(|_| Error::ConnectTimeout(limit))?
+                .map_err(Error::from),
+            None => Ok(endpoint.connect().await?),
+        }
+    }
+}
+
+impl Into<Endpoint> for GrpcEndpoint {
+    fn into(self) -> Endpoint {
+        if let Some(limit) = self.connect_timeout {
+            tracing::warn!(
+                ?limit,
+                "connect_timeout is only enforced by GrpcEndpoint::connect and is dropped here"
+            );
+        }
+        self.endpoint()
+    }
+}
 
-        let endpoint = Channel::builder(
+impl GrpcEndpoint {
+    fn endpoint(self) -> Endpoint {
+        let authority = self.address.as_ref().unwrap_or(&self.domain_name);
+        let mut endpoint = Channel::builder(
             Uri::builder()
-                .scheme("https")
-                .authority(domain_name)
+                .scheme(if self.tls { "https" } else { "http" })
+                .authority(authority.as_str())
                 .path_and_query("/")
                 .build()
                 .expect("Unable to build uri"),
         )
-        .tls_config(tls_config);
-        Ok(GrpcEndpoint { inner: endpoint })
-    }
-}
+        .tcp_keepalive(self.tcp_keepalive)
+        .tcp_nodelay(self.tcp_nodelay);
+        if let Some(timeout) = self.timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+        if let Some(limit) = self.concurrency_limit {
+            endpoint = endpoint.concurrency_limit(limit);
+        }
+        if !self.tls {
+            return endpoint;
+        }
 
-impl Into<Endpoint> for GrpcEndpoint {
-    fn into(self) -> Endpoint {
-        self.inner
+        let mut tls_config = ClientTlsConfig::new().domain_name(&self.domain_name);
+        if let Some(identity) = self.identity {
+            tls_config = tls_config.identity(identity);
+        }
+        if let Some(certificate) = self.ca_certificate {
+            tls_config = tls_config.ca_certificate(certificate);
+        }
+        endpoint.tls_config(tls_config)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tests::stub_server;
 
     #[tokio::test]
     async fn it_gets_credentials() {}
@@ -169,11 +427,75 @@ mod tests {
 
     #[tokio::test]
     async fn it_grabs_the_correct_oauth_token() {
-        let mut creds = Credentials::from_json(include_str!("./tests/credentials.json")).unwrap();
+        let creds = Credentials::from_json(include_str!("./tests/credentials.json")).unwrap();
         let output = creds
-            .request_oauth_token("https://www.googleapis.com/auth/datastore")
+            .fetch_token("https://www.googleapis.com/auth/datastore")
             .await;
         println!("{:?}", output);
-        assert!(creds.oauth_token.is_some());
+        assert!(output.is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_exchanges_jwts_at_the_configured_token_uri() {
+        let server = stub_server::serve(vec![(
+            "/token",
+            r#"{"access_token":"stub-token","expires_in":3599,"token_type":"Bearer"}"#,
+        )])
+        .await;
+        let token_uri = format!("{}/token", server.url());
+        let creds = Credentials::from_json(include_str!("./tests/credentials.json"))
+            .unwrap()
+            .with_token_uri(token_uri.clone());
+
+        let token = creds
+            .fetch_token("https://www.googleapis.com/auth/datastore")
+            .await
+            .unwrap();
+        assert_eq!(token.token, "stub-token");
+
+        let jwt = creds
+            .generate_jwt_request("https://www.googleapis.com/auth/datastore")
+            .unwrap();
+        let claim = jsonwebtoken::dangerous_insecure_decode::<Claim>(&jwt)
+            .unwrap()
+            .claims;
+        assert_eq!(claim.aud, token_uri);
+        assert!(server.requests()[0].contains("grant_type=urn"));
+    }
+
+    #[test]
+    fn it_describes_oauth_errors() {
+        assert_eq!(
+            describe_oauth_error(
+                400,
+                r#"{"error":"invalid_grant","error_description":"Token has been expired or revoked."}"#
+            ),
+            "Token endpoint returned invalid_grant (400): Token has been expired or revoked."
+        );
+        assert_eq!(
+            describe_oauth_error(502, "Bad Gateway"),
+            "Token endpoint returned HTTP 502: Bad Gateway"
+        );
+    }
+
+    #[test]
+    fn it_treats_tokens_inside_the_refresh_margin_as_stale() {
+        let fresh = AccessToken::new("token".to_owned(), Duration::from_secs(3600));
+        assert!(fresh.is_fresh());
+
+        let expiring = AccessToken::new("token".to_owned(), TOKEN_REFRESH_MARGIN);
+        assert!(!expiring.is_fresh());
+
+        let expired = AccessToken {
+            token: "token".to_owned(),
+            expires_at: Some(SystemTime::now() - Duration::from_secs(1)),
+        };
+        assert!(!expired.is_fresh());
+
+        let unbounded = AccessToken {
+            token: "token".to_owned(),
+            expires_at: None,
+        };
+        assert!(unbounded.is_fresh());
     }
 }
@@ -0,0 +1,140 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use http::header::{HeaderValue, AUTHORIZATION};
+use tonic::client::GrpcService;
+use tower_service::Service;
+
+use super::TokenCache;
+use crate::error::{BoxError, Error};
+
+/// Wraps a transport such as a `Channel` so every request carries a bearer token from a
+/// `TokenCache`. Works with any generated client:
+///
+/// ```ignore
+/// let channel = GrpcEndpoint::new("firestore.googleapis.com").connect().await?;
+/// let mut client = FirestoreClient::new(AuthService::new(channel, cache, scope));
+/// ```
+///
+/// Requests cannot be replayed from here, so a call rejected as `UNAUTHENTICATED` fails; the
+/// rejected token is dropped from the cache so the next call fetches a new one.
+#[derive(Clone)]
+pub struct AuthService<S> {
+    inner: S,
+    token: TokenCache,
+    scope: String,
+}
+
+impl<S> AuthService<S> {
+    pub fn new<P: Into<String>>(inner: S, token: TokenCache, scope: P) -> Self {
+        AuthService {
+            inner,
+            token,
+            scope: scope.into(),
+        }
+    }
+}
+
+fn bearer(token: &str) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(&format!("Bearer {}", token)).map_err(Error::token)
+}
+
+// Bounded on `GrpcService` rather than `Service` because that is all a tonic `Channel`
+// implements; every `Service` with a gRPC body is a `GrpcService` too.
+impl<S, B> Service<http::Request<B>> for AuthService<S>
+where
+    S: GrpcService<B> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    S::ResponseBody: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = http::Response<S::ResponseBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        GrpcService::poll_ready(&mut self.inner, cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        // The instance polled ready is the one that must serve the call.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let cache = self.token.clone();
+        let scope = self.scope.clone();
+
+        Box::pin(async move {
+            let token = cache.get(&scope).await?;
+            request.headers_mut().insert(AUTHORIZATION, bearer(&token)?);
+            let response = GrpcService::call(&mut inner, request)
+                .await
+                .map_err(Into::into)?;
+            // Trailers-only responses carry the status in the headers.
+            let unauthenticated = response
+                .headers()
+                .get("grpc-status")
+                .map_or(false, |status| status == "16");
+            if unauthenticated {
+                cache.invalidate(&scope, &token).await;
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::StaticToken;
+    use std::sync::{Arc, Mutex};
+    use tonic::body::BoxBody;
+    use tonic::transport::Channel;
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Service<http::Request<()>> for Recorder {
+        type Response = http::Response<BoxBody>;
+        type Error = BoxError;
+        type Future =
+            Pin<Box<dyn Future<Output = Result<http::Response<BoxBody>, BoxError>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<()>) -> Self::Future {
+            let header = request.headers()[AUTHORIZATION]
+                .to_str()
+                .unwrap()
+                .to_owned();
+            self.seen.lock().unwrap().push(header);
+            Box::pin(async { Ok(http::Response::new(BoxBody::empty())) })
+        }
+    }
+
+    #[tokio::test]
+    async fn it_attaches_bearer_tokens() {
+        let recorder = Recorder::default();
+        let cache = TokenCache::new(StaticToken::new("abc"));
+        let mut service = AuthService::new(recorder.clone(), cache, "scope");
+
+        Service::call(&mut service, http::Request::new(()))
+            .await
+            .unwrap();
+        assert_eq!(
+            *recorder.seen.lock().unwrap(),
+            vec!["Bearer abc".to_owned()]
+        );
+    }
+
+    #[test]
+    fn it_wraps_channels_for_generated_clients() {
+        fn assert_grpc_service<S: tonic::client::GrpcService<BoxBody>>() {}
+        assert_grpc_service::<AuthService<Channel>>();
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::path::Path;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::default_credentials::well_known_file;
+use super::{default_token_uri, AccessToken, OauthResponse, TokenSource};
+use crate::error::Error;
+
+/// End-user credentials, as written by `gcloud auth application-default login`.
+///
+/// Tokens are minted with the refresh-token grant. The scopes are the ones granted when the
+/// refresh token was issued, so the requested scope is not sent.
+#[derive(Deserialize, Clone)]
+pub struct AuthorizedUser {
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+    #[serde(default)]
+    quota_project_id: Option<String>,
+}
+
+impl AuthorizedUser {
+    pub fn new<S: Into<String>>(client_id: S, client_secret: S, refresh_token: S) -> Self {
+        AuthorizedUser {
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            refresh_token: refresh_token.into(),
+            token_uri: default_token_uri(),
+            quota_project_id: None,
+        }
+    }
+
+    /// Redeems the refresh token at `token_uri` instead of Google's OAuth endpoint.
+    pub fn with_token_uri<S: Into<String>>(mut self, token_uri: S) -> Self {
+        self.token_uri = token_uri.into();
+        self
+    }
+
+    pub fn from_json(json_str: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json_str)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let origin = format!("{}", path.as_ref().display());
+        let data = std::fs::read_to_string(path).map_err(|e| Error::credentials(&*origin, e))?;
+        AuthorizedUser::from_json(&data).map_err(|e| Error::credentials(origin, e))
+    }
+
+    /// Loads the credentials left behind by `gcloud auth application-default login`.
+    pub fn from_gcloud() -> Result<Self, Error> {
+        let path = well_known_file().ok_or_else(|| {
+            Error::credentials(
+                "the gcloud well-known file",
+                "Unable to locate the gcloud configuration directory",
+            )
+        })?;
+        AuthorizedUser::from_file(path)
+    }
+}
+
+impl fmt::Debug for AuthorizedUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthorizedUser")
+            .field("client_id", &self.client_id)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl TokenSource for AuthorizedUser {
+    async fn fetch_token(&self, _scope: &str) -> Result<AccessToken, Error> {
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("refresh_token", &self.refresh_token),
+        ];
+        let res = reqwest::Client::new()
+            .post(&self.token_uri)
+            .form(&params)
+            .send()
+            .await?;
+        OauthResponse::read(res).await.map_err(|e| {
+            Error::token(format!(
+                "{}. Run `gcloud auth application-default login` if the refresh token has expired",
+                e
+            ))
+        })
+    }
+
+    fn quota_project_id(&self) -> Option<&str> {
+        self.quota_project_id.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::stub_server;
+
+    #[test]
+    fn it_reads_gcloud_application_default_credentials() {
+        let user = AuthorizedUser::from_json(
+            r#"{
+                "client_id": "test-client.apps.googleusercontent.com",
+                "client_secret": "test-client-secret",
+                "quota_project_id": "test-project",
+                "refresh_token": "1//0refresh",
+                "type": "authorized_user"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(user.refresh_token, "1//0refresh");
+        assert_eq!(user.token_uri, "https://oauth2.googleapis.com/token");
+        assert_eq!(user.quota_project_id(), Some("test-project"));
+        assert!(!format!("{:?}", user).contains("test-client-secret"));
+    }
+
+    #[tokio::test]
+    async fn it_redeems_the_refresh_token_at_the_token_uri() {
+        let server = stub_server::serve(vec![(
+            "/token",
+            r#"{"access_token":"user-token","expires_in":3599,"token_type":"Bearer"}"#,
+        )])
+        .await;
+        let user = AuthorizedUser::new("id", "secret", "refresh")
+            .with_token_uri(format!("{}/token", server.url()));
+
+        let token = user.fetch_token("scope").await.unwrap();
+        assert_eq!(token.token, "user-token");
+        assert!(server.requests()[0].contains("grant_type=refresh_token"));
+    }
+}
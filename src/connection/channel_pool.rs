@@ -0,0 +1,400 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use tonic::body::BoxBody;
+use tonic::client::GrpcService;
+use tonic::transport::Channel;
+use tonic::Status;
+use tower_service::Service;
+use tower_timeout::error::Elapsed;
+
+use super::{GrpcEndpoint, UserAgent};
+use crate::error::Error;
+
+/// How many connections a `ChannelPool` keeps and when it opens more.
+#[derive(Clone, Debug)]
+pub struct PoolOptions {
+    /// Connections opened up front and kept open, replacing any that break.
+    pub size: usize,
+    /// Upper bound on connections once the pool has grown.
+    pub max_size: usize,
+    /// Calls in flight on one connection before the pool opens another. Google front ends
+    /// allow about 100 concurrent streams per connection.
+    pub max_streams: usize,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            size: 1,
+            max_size: 4,
+            max_streams: 100,
+        }
+    }
+}
+
+/// Several connections to one endpoint, with each call sent down the least loaded one.
+///
+/// Clones share the same connections.
+#[derive(Clone)]
+pub struct ChannelPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    endpoint: GrpcEndpoint,
+    options: PoolOptions,
+    subchannels: Mutex<Vec<Arc<Subchannel>>>,
+    // Set while a connection is being opened, so a burst of calls opens only one.
+    connecting: AtomicBool,
+}
+
+struct Subchannel {
+    channel: UserAgent<Channel>,
+    in_flight: AtomicUsize,
+    broken: AtomicBool,
+}
+
+/// A connection checked out for one call. Dropping it releases the stream.
+pub struct Lease {
+    subchannel: Arc<Subchannel>,
+}
+
+/// A leased connection, for use with generated clients. A failure of the transport itself
+/// fails the call with UNAVAILABLE, so it is retried like one the server turned away, and
+/// retires the connection from the pool; statuses returned by the server leave it in service.
+/// The endpoint's per-request timeout fails the call with DEADLINE_EXCEEDED.
+#[derive(Clone)]
+pub struct PooledChannel {
+    subchannel: Arc<Subchannel>,
+    channel: UserAgent<Channel>,
+    // A readiness failure, held for the next call. Generated clients report any error from
+    // `poll_ready` as UNKNOWN.
+    not_ready: Option<Status>,
+}
+
+type ResponseBody = <Channel as GrpcService<BoxBody>>::ResponseBody;
+
+#[derive(Debug, PartialEq)]
+enum Choice {
+    Use(usize),
+    Grow(Option<usize>),
+}
+
+impl ChannelPool {
+    pub async fn connect(endpoint: GrpcEndpoint, options: PoolOptions) -> Result<Self, Error> {
+        let mut subchannels = Vec::with_capacity(options.size);
+        for _ in 0..options.size.max(1) {
+            let channel = endpoint.clone().connect().await?;
+            subchannels.push(Arc::new(Subchannel::new(channel, &endpoint)));
+        }
+
+        Ok(ChannelPool {
+            inner: Arc::new(Inner {
+                endpoint,
+                options,
+                subchannels: Mutex::new(subchannels),
+                connecting: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    /// Checks out the least loaded connection, first opening a new one if the pool is short
+    /// of its size after dropping broken connections, or every connection is at its stream
+    /// limit and the pool may still grow.
+    pub async fn acquire(&self) -> Result<Lease, Error> {
+        let choice = {
+            let mut subchannels = self.inner.subchannels.lock().unwrap();
+            subchannels.retain(|subchannel| !subchannel.broken.load(Ordering::SeqCst));
+            let loads: Vec<usize> = subchannels
+                .iter()
+                .map(|subchannel| subchannel.in_flight.load(Ordering::SeqCst))
+                .collect();
+            match choose(&loads, &self.inner.options) {
+                Choice::Use(index) => return Ok(Lease::new(subchannels[index].clone())),
+                Choice::Grow(fallback) => fallback.map(|index| subchannels[index].clone()),
+            }
+        };
+
+        let connecting = if self.inner.connecting.swap(true, Ordering::SeqCst) {
+            if let Some(subchannel) = choice {
+                return Ok(Lease::new(subchannel));
+            }
+            None
+        } else {
+            Some(Connecting(&self.inner.connecting))
+        };
+        let connected = self.inner.endpoint.clone().connect().await;
+        drop(connecting);
+
+        match (connected, choice) {
+            (Ok(channel), _) => {
+                let subchannel = Arc::new(Subchannel::new(channel, &self.inner.endpoint));
+                let lease = Lease::new(subchannel.clone());
+                self.inner.subchannels.lock().unwrap().push(subchannel);
+                Ok(lease)
+            }
+            // Overloaded connections still beat failing the call.
+            (Err(_), Some(subchannel)) => Ok(Lease::new(subchannel)),
+            (Err(error), None) => Err(error),
+        }
+    }
+
+    /// The number of open connections.
+    pub fn connections(&self) -> usize {
+        self.inner.subchannels.lock().unwrap().len()
+    }
+}
+
+// Clears `Inner::connecting` when the connect it guards ends, also when the acquiring call is
+// dropped part way through, so the pool can still grow afterwards.
+struct Connecting<'a>(&'a AtomicBool);
+
+impl Drop for Connecting<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for ChannelPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelPool")
+            .field("options", &self.inner.options)
+            .field("connections", &self.connections())
+            .finish()
+    }
+}
+
+fn choose(loads: &[usize], options: &PoolOptions) -> Choice {
+    let least_loaded = loads
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, load)| **load)
+        .map(|(index, _)| index);
+    let short = loads.len() < options.size.max(1);
+    let saturated = least_loaded.map_or(true, |index| loads[index] >= options.max_streams);
+
+    match least_loaded {
+        Some(index) if !short && (!saturated || loads.len() >= options.max_size) => {
+            Choice::Use(index)
+        }
+        fallback => Choice::Grow(fallback),
+    }
+}
+
+impl Subchannel {
+    fn new(channel: Channel, endpoint: &GrpcEndpoint) -> Self {
+        Subchannel {
+            channel: UserAgent::new(channel, endpoint.user_agent.clone()),
+            in_flight: AtomicUsize::new(0),
+            broken: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Lease {
+    fn new(subchannel: Arc<Subchannel>) -> Self {
+        subchannel.in_flight.fetch_add(1, Ordering::SeqCst);
+        Lease { subchannel }
+    }
+
+    pub fn channel(&self) -> PooledChannel {
+        PooledChannel {
+            subchannel: self.subchannel.clone(),
+            channel: self.subchannel.channel.clone(),
+            not_ready: None,
+        }
+    }
+
+    /// Retires the connection; the pool opens a replacement on a later call.
+    pub fn mark_broken(&self) {
+        self.subchannel.broken.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.subchannel.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Service<http::Request<BoxBody>> for PooledChannel {
+    type Response = http::Response<ResponseBody>;
+    type Error = Status;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match GrpcService::poll_ready(&mut self.channel, cx) {
+            Poll::Ready(Err(error)) => {
+                self.not_ready = Some(self.subchannel.fail(error));
+                Poll::Ready(Ok(()))
+            }
+            ready => ready.map_err(|error| self.subchannel.fail(error)),
+        }
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        if let Some(status) = self.not_ready.take() {
+            return Box::pin(async { Err(status) });
+        }
+        let subchannel = self.subchannel.clone();
+        let response = GrpcService::call(&mut self.channel, request);
+        Box::pin(async move { response.await.map_err(|error| subchannel.fail(error)) })
+    }
+}
+
+impl Subchannel {
+    // Decides what a failure out of the channel means for the call and for the connection.
+    // Left to tonic, every one of them would be reported as UNKNOWN.
+    fn fail(&self, error: tonic::transport::Error) -> Status {
+        if is_timeout(&error) {
+            return Status::deadline_exceeded(error.to_string());
+        }
+        tracing::debug!(%error, "retiring connection");
+        self.broken.store(true, Ordering::SeqCst);
+        Status::unavailable(error.to_string())
+    }
+}
+
+// Errors out of the channel are transport failures, except for the endpoint's per-request
+// timeout, which says nothing about the connection.
+fn is_timeout(error: &(dyn StdError + 'static)) -> bool {
+    let mut cause = Some(error);
+    while let Some(error) = cause {
+        if error.is::<Elapsed>() {
+            return true;
+        }
+        cause = error.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn it_balances_and_grows_on_stream_limits() {
+        let options = PoolOptions {
+            size: 2,
+            max_size: 3,
+            max_streams: 10,
+        };
+        assert_eq!(choose(&[4, 2], &options), Choice::Use(1));
+        assert_eq!(choose(&[10, 10], &options), Choice::Grow(Some(0)));
+        assert_eq!(choose(&[10, 10, 10], &options), Choice::Use(0));
+    }
+
+    #[test]
+    fn it_refills_after_broken_connections_are_dropped() {
+        let options = PoolOptions {
+            size: 2,
+            ..PoolOptions::default()
+        };
+        assert_eq!(choose(&[0], &options), Choice::Grow(Some(0)));
+        assert_eq!(choose(&[], &options), Choice::Grow(None));
+    }
+
+    #[tokio::test]
+    async fn it_can_grow_again_after_a_connect_is_abandoned() {
+        // Accepts connections into the backlog but never reads or replies, so plaintext
+        // connections open while a TLS handshake waits forever.
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let authority = silent.local_addr().unwrap().to_string();
+        let channel = GrpcEndpoint::insecure(&authority).connect().await.unwrap();
+        let endpoint = GrpcEndpoint::new("localhost").address(&authority);
+        let subchannel = Arc::new(Subchannel::new(channel, &endpoint));
+        let pool = ChannelPool {
+            inner: Arc::new(Inner {
+                endpoint,
+                options: PoolOptions {
+                    size: 1,
+                    max_size: 2,
+                    max_streams: 1,
+                },
+                subchannels: Mutex::new(vec![subchannel]),
+                connecting: AtomicBool::new(false),
+            }),
+        };
+
+        let _busy = pool.acquire().await.unwrap();
+        let grow = tokio::time::timeout(Duration::from_millis(50), pool.acquire());
+        assert!(grow.await.is_err());
+        assert!(!pool.inner.connecting.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn it_fails_calls_on_broken_connections_as_unavailable() {
+        use crate::google::firestore::v1::firestore_client::FirestoreClient;
+        use crate::google::firestore::v1::GetDocumentRequest;
+        use std::net::SocketAddr;
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        // Reads HTTP/2 frames until the request's HEADERS arrive, then hangs up.
+        let mut listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let authority = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut preface = [0; 24];
+                    socket.read_exact(&mut preface).await?;
+                    loop {
+                        let mut header = [0; 9];
+                        socket.read_exact(&mut header).await?;
+                        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]);
+                        let mut payload = vec![0; length as usize];
+                        socket.read_exact(&mut payload).await?;
+                        if header[3] == 0x1 {
+                            return Ok::<_, std::io::Error>(());
+                        }
+                    }
+                });
+            }
+        });
+        let endpoint = GrpcEndpoint::insecure(&authority);
+        let pool = ChannelPool::connect(endpoint, PoolOptions::default())
+            .await
+            .unwrap();
+
+        let lease = pool.acquire().await.unwrap();
+        let status = FirestoreClient::new(lease.channel())
+            .get_document(GetDocumentRequest::default())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert!(Error::from(status).is_retryable());
+        assert!(lease.subchannel.broken.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn it_only_retires_connections_on_transport_failures() {
+        #[derive(Debug)]
+        struct Wrapped(Box<dyn StdError + 'static>);
+
+        impl fmt::Display for Wrapped {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "transport error")
+            }
+        }
+
+        impl StdError for Wrapped {
+            fn source(&self) -> Option<&(dyn StdError + 'static)> {
+                Some(&*self.0)
+            }
+        }
+
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(!is_timeout(&Wrapped(Box::new(reset))));
+        assert!(is_timeout(&Wrapped(Box::new(Elapsed::new()))));
+    }
+}
@@ -0,0 +1,260 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::{
+    AccessToken, AuthorizedUser, Credentials, ExternalAccount, ImpersonatedServiceAccount,
+    MetadataServer, TokenSource,
+};
+use crate::error::{BoxError, Error};
+
+const WELL_KNOWN_FILE: &str = "application_default_credentials.json";
+
+/// Credentials found through Application Default Credentials discovery.
+pub enum ApplicationCredentials {
+    ServiceAccount(Credentials),
+    AuthorizedUser(AuthorizedUser),
+    ExternalAccount(ExternalAccount),
+    ImpersonatedServiceAccount(ImpersonatedServiceAccount),
+    MetadataServer(MetadataServer),
+}
+
+#[derive(Deserialize)]
+struct CredentialsFile {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ImpersonatedCredentialsFile {
+    service_account_impersonation_url: String,
+    #[serde(default)]
+    delegates: Vec<String>,
+    source_credentials: serde_json::Value,
+}
+
+impl ApplicationCredentials {
+    /// Parses a credentials file, dispatching on its `type` field.
+    pub fn from_json(json_str: &str) -> Result<Self, Error> {
+        ApplicationCredentials::parse(json_str)
+            .map_err(|e| Error::credentials("the credentials JSON", e))
+    }
+
+    fn parse(json_str: &str) -> Result<Self, BoxError> {
+        let file: CredentialsFile = serde_json::from_str(json_str)?;
+        match file.kind.as_deref() {
+            Some("service_account") => Ok(ApplicationCredentials::ServiceAccount(
+                Credentials::from_json(json_str)?,
+            )),
+            Some("authorized_user") => Ok(ApplicationCredentials::AuthorizedUser(
+                AuthorizedUser::from_json(json_str)?,
+            )),
+            Some("external_account") => Ok(ApplicationCredentials::ExternalAccount(
+                ExternalAccount::from_json(json_str)?,
+            )),
+            Some("impersonated_service_account") => {
+                let file: ImpersonatedCredentialsFile = serde_json::from_str(json_str)?;
+                let source = ApplicationCredentials::parse(&file.source_credentials.to_string())?;
+                let impersonated = ImpersonatedServiceAccount::from_url(
+                    source,
+                    &file.service_account_impersonation_url,
+                )?;
+                Ok(ApplicationCredentials::ImpersonatedServiceAccount(
+                    impersonated.delegates(file.delegates),
+                ))
+            }
+            Some(kind) => Err(format!("Unknown credentials type `{}`", kind).into()),
+            None => Err("Credentials file has no `type` field".into()),
+        }
+    }
+
+    /// Follows the Application Default Credentials order: the file named by
+    /// `GOOGLE_APPLICATION_CREDENTIALS`, then gcloud's well-known file, then the metadata server.
+    pub async fn find() -> Result<Self, Error> {
+        if let Ok(path) = std::env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+            let origin = format!("GOOGLE_APPLICATION_CREDENTIALS ({})", path);
+            return load_file(origin, Path::new(&path));
+        }
+
+        let well_known = well_known_file();
+        if let Some(path) = well_known.as_ref().filter(|path| path.exists()) {
+            let origin = format!("the gcloud well-known file ({})", path.display());
+            return load_file(origin, path);
+        }
+
+        let metadata = MetadataServer::new();
+        if metadata.is_available().await {
+            return Ok(ApplicationCredentials::MetadataServer(metadata));
+        }
+
+        let searched = match well_known {
+            Some(path) => format!("{}", path.display()),
+            None => "the gcloud well-known file".to_owned(),
+        };
+        Err(Error::credentials(
+            "the environment",
+            format!(
+                "GOOGLE_APPLICATION_CREDENTIALS is not set, {} does not exist and no metadata server is reachable",
+                searched
+            ),
+        ))
+    }
+
+    /// The project to bill requests to: the key file's project for service accounts, otherwise
+    /// the one named by the environment (see `project_id_from_env`) or, on Google Cloud, the
+    /// project the workload runs in.
+    pub async fn project_id(&self) -> Result<String, Error> {
+        if let ApplicationCredentials::ServiceAccount(credentials) = self {
+            return Ok(credentials.project_id.clone());
+        }
+        if let Some(project_id) = project_id_from_env() {
+            return Ok(project_id);
+        }
+        match self {
+            ApplicationCredentials::MetadataServer(metadata) => metadata.project_id().await,
+            _ => Err(Error::credentials(
+                "the environment",
+                "No project id in the credentials and neither GOOGLE_CLOUD_PROJECT nor GCLOUD_PROJECT is set",
+            )),
+        }
+    }
+}
+
+impl From<Credentials> for ApplicationCredentials {
+    fn from(credentials: Credentials) -> Self {
+        ApplicationCredentials::ServiceAccount(credentials)
+    }
+}
+
+impl From<AuthorizedUser> for ApplicationCredentials {
+    fn from(user: AuthorizedUser) -> Self {
+        ApplicationCredentials::AuthorizedUser(user)
+    }
+}
+
+impl From<ExternalAccount> for ApplicationCredentials {
+    fn from(account: ExternalAccount) -> Self {
+        ApplicationCredentials::ExternalAccount(account)
+    }
+}
+
+impl From<ImpersonatedServiceAccount> for ApplicationCredentials {
+    fn from(impersonated: ImpersonatedServiceAccount) -> Self {
+        ApplicationCredentials::ImpersonatedServiceAccount(impersonated)
+    }
+}
+
+impl From<MetadataServer> for ApplicationCredentials {
+    fn from(metadata: MetadataServer) -> Self {
+        ApplicationCredentials::MetadataServer(metadata)
+    }
+}
+
+/// The project named by `GOOGLE_CLOUD_PROJECT`, or failing that the older `GCLOUD_PROJECT`.
+pub(crate) fn project_id_from_env() -> Option<String> {
+    std::env::var("GOOGLE_CLOUD_PROJECT")
+        .or_else(|_| std::env::var("GCLOUD_PROJECT"))
+        .ok()
+}
+
+#[async_trait]
+impl TokenSource for ApplicationCredentials {
+    async fn fetch_token(&self, scope: &str) -> Result<AccessToken, Error> {
+        match self {
+            ApplicationCredentials::ServiceAccount(credentials) => {
+                credentials.fetch_token(scope).await
+            }
+            ApplicationCredentials::AuthorizedUser(user) => user.fetch_token(scope).await,
+            ApplicationCredentials::ExternalAccount(account) => account.fetch_token(scope).await,
+            ApplicationCredentials::ImpersonatedServiceAccount(impersonated) => {
+                impersonated.fetch_token(scope).await
+            }
+            ApplicationCredentials::MetadataServer(metadata) => metadata.fetch_token(scope).await,
+        }
+    }
+
+    fn quota_project_id(&self) -> Option<&str> {
+        match self {
+            ApplicationCredentials::ServiceAccount(credentials) => credentials.quota_project_id(),
+            ApplicationCredentials::AuthorizedUser(user) => user.quota_project_id(),
+            ApplicationCredentials::ExternalAccount(account) => account.quota_project_id(),
+            ApplicationCredentials::ImpersonatedServiceAccount(impersonated) => {
+                impersonated.quota_project_id()
+            }
+            ApplicationCredentials::MetadataServer(_) => None,
+        }
+    }
+}
+
+fn load_file(origin: String, path: &Path) -> Result<ApplicationCredentials, Error> {
+    let data = std::fs::read_to_string(path).map_err(|e| Error::credentials(&*origin, e))?;
+    ApplicationCredentials::parse(&data).map_err(|e| Error::credentials(origin, e))
+}
+
+pub(super) fn well_known_file() -> Option<PathBuf> {
+    if let Ok(config_dir) = std::env::var("CLOUDSDK_CONFIG") {
+        return Some(PathBuf::from(config_dir).join(WELL_KNOWN_FILE));
+    }
+    let config_dir = if cfg!(windows) {
+        PathBuf::from(std::env::var("APPDATA").ok()?).join("gcloud")
+    } else {
+        PathBuf::from(std::env::var("HOME").ok()?)
+            .join(".config")
+            .join("gcloud")
+    };
+    Some(config_dir.join(WELL_KNOWN_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_dispatches_on_the_credentials_type() {
+        let user = ApplicationCredentials::from_json(
+            r#"{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"refresh"}"#,
+        );
+        assert!(matches!(
+            user,
+            Ok(ApplicationCredentials::AuthorizedUser(_))
+        ));
+
+        let unknown = ApplicationCredentials::from_json(r#"{"type":"carrier_pigeon"}"#);
+        assert!(unknown
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("carrier_pigeon"));
+
+        let untyped = ApplicationCredentials::from_json(r#"{"client_id":"id"}"#);
+        assert!(untyped.is_err());
+
+        let impersonated = ApplicationCredentials::from_json(
+            r#"{
+                "type": "impersonated_service_account",
+                "service_account_impersonation_url": "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/target@test.iam.gserviceaccount.com:generateAccessToken",
+                "delegates": [],
+                "source_credentials": {"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"refresh"}
+            }"#,
+        );
+        assert!(matches!(
+            impersonated,
+            Ok(ApplicationCredentials::ImpersonatedServiceAccount(_))
+        ));
+    }
+
+    #[test]
+    fn it_names_the_source_that_failed() {
+        let error = load_file(
+            "GOOGLE_APPLICATION_CREDENTIALS (/nonexistent.json)".to_owned(),
+            Path::new("/nonexistent.json"),
+        )
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("Unable to load credentials from GOOGLE_APPLICATION_CREDENTIALS"));
+        assert!(std::error::Error::source(&error).is_some());
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::iam::generate_access_token;
+use super::{AccessToken, OauthResponse, TokenSource};
+use crate::error::Error;
+
+const DEFAULT_STS_ENDPOINT: &str = "https://sts.googleapis.com/v1/token";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const IMPERSONATED_TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+
+fn default_sts_endpoint() -> String {
+    DEFAULT_STS_ENDPOINT.to_owned()
+}
+
+/// Workload Identity Federation credentials (`"type": "external_account"`).
+///
+/// A subject token issued by another identity provider is read from a file or URL, exchanged
+/// for a Google access token at the STS endpoint and, when
+/// `service_account_impersonation_url` is set, used to impersonate a service account.
+/// AWS `environment_id` sources are not supported.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ExternalAccount {
+    audience: String,
+    subject_token_type: String,
+    #[serde(rename = "token_url", default = "default_sts_endpoint")]
+    sts_endpoint: String,
+    service_account_impersonation_url: Option<String>,
+    credential_source: CredentialSource,
+    #[serde(default)]
+    quota_project_id: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct CredentialSource {
+    file: Option<String>,
+    url: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    format: Option<SubjectTokenFormat>,
+    environment_id: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct SubjectTokenFormat {
+    #[serde(rename = "type")]
+    kind: String,
+    subject_token_field_name: Option<String>,
+}
+
+impl ExternalAccount {
+    pub fn from_json(json_str: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(json_str)?)
+    }
+
+    /// Exchanges subject tokens at `endpoint` instead of the `token_url` from the file.
+    pub fn with_sts_endpoint<S: Into<String>>(mut self, endpoint: S) -> Self {
+        self.sts_endpoint = endpoint.into();
+        self
+    }
+
+    /// Reads the subject token from `url` instead of the file's credential source.
+    pub fn with_subject_token_url<S: Into<String>>(mut self, url: S) -> Self {
+        self.credential_source.file = None;
+        self.credential_source.url = Some(url.into());
+        self
+    }
+
+    pub fn with_impersonation_url<S: Into<String>>(mut self, url: S) -> Self {
+        self.service_account_impersonation_url = Some(url.into());
+        self
+    }
+
+    async fn subject_token(&self) -> Result<String, Error> {
+        let source = &self.credential_source;
+        let raw = if let Some(file) = &source.file {
+            std::fs::read_to_string(file)
+                .map_err(|e| Error::credentials(format!("the subject token file {}", file), e))?
+        } else if let Some(url) = &source.url {
+            let mut request = reqwest::Client::new().get(url);
+            for (name, value) in &source.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            request
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|e| Error::credentials(format!("the subject token URL {}", url), e))?
+                .text()
+                .await?
+        } else if let Some(environment_id) = &source.environment_id {
+            return Err(Error::credentials(
+                "the external account credential source",
+                format!("`{}` credential sources are not supported", environment_id),
+            ));
+        } else {
+            return Err(Error::credentials(
+                "the external account credential source",
+                "Neither `file` nor `url` is set",
+            ));
+        };
+        parse_subject_token(&raw, source.format.as_ref())
+    }
+
+    async fn exchange(&self, subject_token: &str, scope: &str) -> Result<AccessToken, Error> {
+        let params = [
+            (
+                "grant_type",
+                "urn:ietf:params:oauth:grant-type:token-exchange",
+            ),
+            ("audience", &self.audience),
+            ("scope", scope),
+            (
+                "requested_token_type",
+                "urn:ietf:params:oauth:token-type:access_token",
+            ),
+            ("subject_token", subject_token),
+            ("subject_token_type", &self.subject_token_type),
+        ];
+        let res = reqwest::Client::new()
+            .post(&self.sts_endpoint)
+            .form(&params)
+            .send()
+            .await?;
+        OauthResponse::read(res).await
+    }
+}
+
+fn parse_subject_token(raw: &str, format: Option<&SubjectTokenFormat>) -> Result<String, Error> {
+    match format {
+        Some(SubjectTokenFormat {
+            kind,
+            subject_token_field_name,
+        }) if kind == "json" => {
+            let field = subject_token_field_name.as_ref().ok_or_else(|| {
+                Error::credentials(
+                    "the external account credential source",
+                    "`subject_token_field_name` is required for json subject tokens",
+                )
+            })?;
+            let body: HashMap<String, serde_json::Value> = serde_json::from_str(raw)?;
+            match body.get(field).and_then(|value| value.as_str()) {
+                Some(token) => Ok(token.to_owned()),
+                None => Err(Error::credentials(
+                    "the external account credential source",
+                    format!("The subject token response has no `{}` field", field),
+                )),
+            }
+        }
+        _ => Ok(raw.trim().to_owned()),
+    }
+}
+
+#[async_trait]
+impl TokenSource for ExternalAccount {
+    async fn fetch_token(&self, scope: &str) -> Result<AccessToken, Error> {
+        let subject_token = self.subject_token().await?;
+        match &self.service_account_impersonation_url {
+            Some(url) => {
+                let federated = self.exchange(&subject_token, CLOUD_PLATFORM_SCOPE).await?;
+                generate_access_token(
+                    url,
+                    &federated.token,
+                    &[],
+                    scope,
+                    IMPERSONATED_TOKEN_LIFETIME,
+                )
+                .await
+            }
+            None => self.exchange(&subject_token, scope).await,
+        }
+    }
+
+    fn quota_project_id(&self) -> Option<&str> {
+        self.quota_project_id.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::stub_server;
+
+    #[tokio::test]
+    async fn it_exchanges_a_url_subject_token_and_impersonates() {
+        let server = stub_server::serve(vec![
+            ("/subject", r#"{"id_token":"subject-jwt"}"#),
+            (
+                "/v1/token",
+                r#"{"access_token":"federated-token","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#,
+            ),
+            (
+                "/v1/projects/-/serviceAccounts/",
+                r#"{"accessToken":"impersonated-token","expireTime":"2030-01-01T00:00:00Z"}"#,
+            ),
+        ])
+        .await;
+        let account = ExternalAccount::from_json(&format!(
+            r#"{{
+                "type": "external_account",
+                "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/oidc",
+                "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+                "token_url": "{url}/v1/token",
+                "service_account_impersonation_url": "{url}/v1/projects/-/serviceAccounts/sa@test.iam.gserviceaccount.com:generateAccessToken",
+                "credential_source": {{
+                    "url": "{url}/subject",
+                    "headers": {{"Metadata": "True"}},
+                    "format": {{"type": "json", "subject_token_field_name": "id_token"}}
+                }}
+            }}"#,
+            url = server.url()
+        ))
+        .unwrap();
+
+        let token = account
+            .fetch_token("https://www.googleapis.com/auth/datastore")
+            .await
+            .unwrap();
+        assert_eq!(token.token, "impersonated-token");
+
+        let requests = server.requests();
+        assert!(requests[1].contains("subject_token=subject-jwt"));
+        assert!(requests[2]
+            .to_lowercase()
+            .contains("bearer federated-token"));
+    }
+
+    #[tokio::test]
+    async fn it_reads_text_subject_tokens_from_files() {
+        let path = std::env::temp_dir().join("external-account-subject-token");
+        std::fs::write(&path, "file-subject-token\n").unwrap();
+        let account = ExternalAccount::from_json(&format!(
+            r#"{{
+                "audience": "aud",
+                "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+                "credential_source": {{"file": "{}"}}
+            }}"#,
+            path.display()
+        ))
+        .unwrap();
+
+        assert_eq!(account.subject_token().await.unwrap(), "file-subject-token");
+        assert_eq!(account.sts_endpoint, DEFAULT_STS_ENDPOINT);
+    }
+}
@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use super::iam::{
+    generate_access_token, generate_access_token_url, DEFAULT_IAM_CREDENTIALS_ENDPOINT,
+};
+use super::{AccessToken, TokenCache, TokenSource};
+use crate::error::Error;
+use async_trait::async_trait;
+
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Acts as `target_principal` by calling IAM Credentials `generateAccessToken` with a token
+/// from another source, optionally through a chain of delegate service accounts.
+///
+/// The source token is cached separately, so it is only refreshed when it goes stale itself.
+pub struct ImpersonatedServiceAccount {
+    source: TokenCache,
+    target_principal: String,
+    url: String,
+    delegates: Vec<String>,
+    scopes: Option<Vec<String>>,
+    lifetime: Duration,
+}
+
+impl ImpersonatedServiceAccount {
+    pub fn new<T: TokenSource + 'static, S: Into<String>>(source: T, target_principal: S) -> Self {
+        let target_principal = target_principal.into();
+        ImpersonatedServiceAccount {
+            source: TokenCache::new(source),
+            url: generate_access_token_url(DEFAULT_IAM_CREDENTIALS_ENDPOINT, &target_principal),
+            target_principal,
+            delegates: Vec::new(),
+            scopes: None,
+            lifetime: DEFAULT_LIFETIME,
+        }
+    }
+
+    /// Service accounts to hop through, in order, each granted token creator on the next.
+    pub fn delegates<S: Into<String>>(mut self, delegates: Vec<S>) -> Self {
+        self.delegates = delegates
+            .into_iter()
+            .map(|delegate| {
+                let delegate = delegate.into();
+                if delegate.starts_with("projects/") {
+                    delegate
+                } else {
+                    format!("projects/-/serviceAccounts/{}", delegate)
+                }
+            })
+            .collect();
+        self
+    }
+
+    /// Limits the impersonated token to these scopes, whatever the caller asks for.
+    pub fn scopes<S: Into<String>>(mut self, scopes: Vec<S>) -> Self {
+        self.scopes = Some(scopes.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// How long impersonated tokens live. Anything over an hour needs the
+    /// `iam.allowServiceAccountCredentialLifetimeExtension` organization policy.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Calls `generateAccessToken` on `endpoint` instead of `iamcredentials.googleapis.com`.
+    pub fn with_endpoint<S: AsRef<str>>(mut self, endpoint: S) -> Self {
+        self.url = generate_access_token_url(endpoint.as_ref(), &self.target_principal);
+        self
+    }
+
+    /// Builds from the full `generateAccessToken` URL found in `impersonated_service_account`
+    /// files.
+    pub(super) fn from_url<T: TokenSource + 'static>(source: T, url: &str) -> Result<Self, Error> {
+        let target_principal = url
+            .rsplit("serviceAccounts/")
+            .next()
+            .and_then(|rest| rest.split(":generateAccessToken").next())
+            .filter(|target| !target.is_empty() && *target != url)
+            .ok_or_else(|| {
+                Error::credentials(
+                    "service_account_impersonation_url",
+                    format!("`{}` does not name a service account", url),
+                )
+            })?;
+        let mut impersonated = ImpersonatedServiceAccount::new(source, target_principal);
+        impersonated.url = url.to_owned();
+        Ok(impersonated)
+    }
+}
+
+#[async_trait]
+impl TokenSource for ImpersonatedServiceAccount {
+    async fn fetch_token(&self, scope: &str) -> Result<AccessToken, Error> {
+        let source_token = self.source.get(CLOUD_PLATFORM_SCOPE).await?;
+        let scope = match &self.scopes {
+            Some(scopes) => scopes.join(" "),
+            None => scope.to_owned(),
+        };
+        generate_access_token(
+            &self.url,
+            &source_token,
+            &self.delegates,
+            &scope,
+            self.lifetime,
+        )
+        .await
+    }
+
+    fn quota_project_id(&self) -> Option<&str> {
+        self.source.quota_project_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::from_fn;
+    use crate::tests::stub_server;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn it_impersonates_through_delegates_and_caches_the_source_token() {
+        let server = stub_server::serve(vec![(
+            "/v1/projects/-/serviceAccounts/target@test.iam.gserviceaccount.com:generateAccessToken",
+            r#"{"accessToken":"impersonated-token","expireTime":"2030-01-01T00:00:00Z"}"#,
+        )])
+        .await;
+        let source_fetches = Arc::new(AtomicUsize::new(0));
+        let counter = source_fetches.clone();
+        let source = from_fn(move |_scope| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async {
+                Ok(AccessToken::new(
+                    "source-token".to_owned(),
+                    DEFAULT_LIFETIME,
+                ))
+            }
+        });
+        let impersonated =
+            ImpersonatedServiceAccount::new(source, "target@test.iam.gserviceaccount.com")
+                .delegates(vec!["delegate@test.iam.gserviceaccount.com"])
+                .scopes(vec!["https://www.googleapis.com/auth/datastore"])
+                .lifetime(Duration::from_secs(600))
+                .with_endpoint(server.url());
+
+        let token = impersonated.fetch_token("ignored").await.unwrap();
+        assert_eq!(token.token, "impersonated-token");
+        impersonated.fetch_token("ignored").await.unwrap();
+        assert_eq!(source_fetches.load(Ordering::SeqCst), 1);
+
+        let request = &server.requests()[0];
+        assert!(request.to_lowercase().contains("bearer source-token"));
+        assert!(request.contains(
+            r#""delegates":["projects/-/serviceAccounts/delegate@test.iam.gserviceaccount.com"]"#
+        ));
+        assert!(request.contains(r#""scope":["https://www.googleapis.com/auth/datastore"]"#));
+        assert!(request.contains(r#""lifetime":"600s""#));
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use super::{AccessToken, OauthResponse, TokenSource};
+use crate::error::Error;
+
+const DEFAULT_METADATA_HOST: &str = "metadata.google.internal";
+
+// Off GCP the host may resolve to something that never answers, so discovery must not wait
+// for the OS connect timeout.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Credentials of the service account attached to a GCE VM, GKE node or Cloud Run service,
+/// served by the instance metadata server.
+///
+/// The host is read from `GCE_METADATA_HOST` when set, so a stub server can stand in for it.
+#[derive(Clone)]
+pub struct MetadataServer {
+    host: String,
+    service_account: String,
+    client: reqwest::Client,
+}
+
+impl MetadataServer {
+    pub fn new() -> Self {
+        let host =
+            std::env::var("GCE_METADATA_HOST").unwrap_or_else(|_| DEFAULT_METADATA_HOST.to_owned());
+        MetadataServer::with_host(host)
+    }
+
+    pub fn with_host<S: Into<String>>(host: S) -> Self {
+        MetadataServer {
+            host: host.into(),
+            service_account: "default".to_owned(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Uses a service account other than the instance's default one.
+    pub fn service_account<S: Into<String>>(mut self, email: S) -> Self {
+        self.service_account = email.into();
+        self
+    }
+
+    pub async fn project_id(&self) -> Result<String, Error> {
+        let read = async {
+            let res = self.get("project/project-id").send().await?;
+            res.error_for_status()?.text().await
+        };
+        match read.await {
+            Ok(project_id) => Ok(project_id.trim().to_owned()),
+            Err(e) => Err(Error::credentials("the metadata server", e)),
+        }
+    }
+
+    /// Whether a metadata server answers on the configured host within a second.
+    pub async fn is_available(&self) -> bool {
+        match self.get("").timeout(PROBE_TIMEOUT).send().await {
+            Ok(res) => res
+                .headers()
+                .get("Metadata-Flavor")
+                .map(|flavor| flavor == "Google")
+                .unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .get(&format!("http://{}/computeMetadata/v1/{}", self.host, path))
+            .header("Metadata-Flavor", "Google")
+    }
+}
+
+impl Default for MetadataServer {
+    fn default() -> Self {
+        MetadataServer::new()
+    }
+}
+
+#[async_trait]
+impl TokenSource for MetadataServer {
+    async fn fetch_token(&self, scope: &str) -> Result<AccessToken, Error> {
+        let path = format!("instance/service-accounts/{}/token", self.service_account);
+        let res = self
+            .get(&path)
+            .query(&[("scopes", scope)])
+            .send()
+            .await?
+            .error_for_status()?;
+        let body = res.json::<OauthResponse>().await?;
+        Ok(body.into_access_token())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::stub_server;
+
+    #[tokio::test]
+    async fn it_reads_tokens_and_project_from_the_metadata_server() {
+        let server = stub_server::serve(vec![
+            (
+                "/computeMetadata/v1/instance/service-accounts/default/token",
+                r#"{"access_token":"metadata-token","expires_in":3599,"token_type":"Bearer"}"#,
+            ),
+            ("/computeMetadata/v1/project/project-id", "test-project"),
+        ])
+        .await;
+        let metadata = MetadataServer::with_host(server.addr.to_string());
+
+        let token = metadata
+            .fetch_token("https://www.googleapis.com/auth/datastore")
+            .await
+            .unwrap();
+        assert_eq!(token.token, "metadata-token");
+        assert!(token.is_fresh());
+        assert_eq!(metadata.project_id().await.unwrap(), "test-project");
+        assert!(server
+            .requests()
+            .iter()
+            .all(|req| req.to_lowercase().contains("metadata-flavor: google")));
+    }
+
+    #[tokio::test]
+    async fn it_gives_up_probing_a_host_that_never_answers() {
+        // Accepts connections into the backlog but never reads or replies.
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let metadata = MetadataServer::with_host(silent.local_addr().unwrap().to_string());
+
+        let started = std::time::Instant::now();
+        assert!(!metadata.is_available().await);
+        assert!(started.elapsed() < PROBE_TIMEOUT * 3);
+    }
+}
@@ -0,0 +1,64 @@
+use async_trait::async_trait;
+
+use super::{AccessToken, Claim, Credentials, TokenSource, JWT_LIFETIME};
+use crate::error::Error;
+
+/// Authorizes with JWTs signed by the service account key itself and addressed to the API
+/// endpoint (e.g. `https://firestore.googleapis.com/`), which Google APIs accept directly.
+///
+/// This skips the round-trip to the OAuth token endpoint that `Credentials` makes on every
+/// cold start. The JWT carries the key id as `kid` so the API can find the public key.
+#[derive(Clone)]
+pub struct SelfSignedJwt {
+    credentials: Credentials,
+    audience: String,
+}
+
+impl SelfSignedJwt {
+    pub fn new<S: Into<String>>(credentials: Credentials, audience: S) -> Self {
+        SelfSignedJwt {
+            credentials,
+            audience: audience.into(),
+        }
+    }
+
+    fn mint(&self) -> Result<AccessToken, Error> {
+        let claim = Claim::self_signed(self.credentials.client_email.clone(), &self.audience);
+        let jwt = self
+            .credentials
+            .sign(&claim, Some(self.credentials.private_key_id.clone()))?;
+        Ok(AccessToken::new(jwt, JWT_LIFETIME))
+    }
+}
+
+#[async_trait]
+impl TokenSource for SelfSignedJwt {
+    async fn fetch_token(&self, _scope: &str) -> Result<AccessToken, Error> {
+        self.mint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{dangerous_insecure_decode, decode_header};
+
+    #[test]
+    fn it_signs_jwts_for_the_service_endpoint() {
+        let creds = Credentials::from_json(include_str!("../tests/credentials.json")).unwrap();
+        let source = creds
+            .clone()
+            .self_signed_jwt("https://firestore.googleapis.com/");
+
+        let token = source.mint().unwrap();
+        let header = decode_header(&token.token).unwrap();
+        assert_eq!(header.kid, Some(creds.private_key_id.clone()));
+
+        let claim = dangerous_insecure_decode::<Claim>(&token.token)
+            .unwrap()
+            .claims;
+        assert_eq!(claim.aud, "https://firestore.googleapis.com/");
+        assert_eq!(claim.sub, Some(creds.client_email.clone()));
+        assert!(claim.scope.is_none());
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::sync::{oneshot, Mutex};
+use tracing_futures::Instrument;
+
+use super::{AccessToken, TokenSource};
+use crate::error::Error;
+use crate::metrics::Metrics;
+
+// How often an idle refresher wakes up to look for newly cached scopes.
+const REFRESHER_IDLE_POLL: Duration = Duration::from_secs(60);
+
+// The soonest the refresher fetches again after a successful refresh. Some sources, like the
+// metadata server, hand out the same token until a few minutes before it expires, which may be
+// well inside the lead time.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Caches tokens from a source per scope set, refetching only once a token goes stale.
+///
+/// Clones share the same cache. Concurrent callers asking for the same scopes wait on a
+/// single fetch and all receive its outcome.
+#[derive(Clone)]
+pub struct TokenCache {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    source: Box<dyn TokenSource>,
+    slots: SyncMutex<HashMap<Vec<String>, Arc<Slot>>>,
+    // Dropped along with the cache, which tells a running refresher to stop.
+    stop_refresher: SyncMutex<Option<oneshot::Sender<()>>>,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+#[derive(Default)]
+struct Slot {
+    // Bumped every time a fetch finishes, so waiters can tell that one completed while they
+    // were queued on `state`.
+    fetches: AtomicU64,
+    state: Mutex<SlotState>,
+}
+
+#[derive(Default)]
+struct SlotState {
+    token: Option<AccessToken>,
+    last_error: Option<Arc<Error>>,
+}
+
+impl TokenCache {
+    pub fn new<T: TokenSource + 'static>(source: T) -> Self {
+        TokenCache::build(Box::new(source), None)
+    }
+
+    /// A cache that reports every token fetch, including background refreshes, to `metrics`.
+    /// Every client sharing the cache reports to the same sink.
+    pub fn with_metrics<T: TokenSource + 'static>(source: T, metrics: Arc<dyn Metrics>) -> Self {
+        TokenCache::build(Box::new(source), Some(metrics))
+    }
+
+    fn build(source: Box<dyn TokenSource>, metrics: Option<Arc<dyn Metrics>>) -> Self {
+        TokenCache {
+            inner: Arc::new(Inner {
+                source,
+                slots: SyncMutex::new(HashMap::new()),
+                stop_refresher: SyncMutex::new(None),
+                metrics,
+            }),
+        }
+    }
+
+    /// A fresh token for `scope`, which may list several space-separated scopes.
+    pub async fn get(&self, scope: &str) -> Result<String, Error> {
+        let slot = self.slot(scope);
+        let observed = slot.fetches.load(Ordering::SeqCst);
+        let mut state = slot.state.lock().await;
+
+        if let Some(token) = state.token.as_ref().filter(|token| token.is_fresh()) {
+            return Ok(token.token.clone());
+        }
+        if slot.fetches.load(Ordering::SeqCst) != observed {
+            if let Some(error) = &state.last_error {
+                return Err(Error::Shared(error.clone()));
+            }
+        }
+
+        // Held across the fetch so concurrent callers queue up behind it.
+        let result = self.fetch(scope).await;
+        slot.fetches.fetch_add(1, Ordering::SeqCst);
+        match result {
+            Ok(token) => Ok(store(&mut state, token)),
+            // A token that is past its refresh point may still be accepted for a while, so it is
+            // kept for the refresher to retry rather than dropped.
+            Err(error) => {
+                let error = Arc::new(error);
+                state.last_error = Some(error.clone());
+                Err(Error::Shared(error))
+            }
+        }
+    }
+
+    async fn fetch(&self, scope: &str) -> Result<AccessToken, Error> {
+        let started = Instant::now();
+        let result = self
+            .inner
+            .source
+            .fetch_token(scope)
+            .instrument(tracing::debug_span!("token.fetch", scope))
+            .await;
+        if let Some(metrics) = &self.inner.metrics {
+            metrics.token_fetched(result.is_ok(), started.elapsed());
+        }
+        if let Err(error) = &result {
+            tracing::warn!(scope, %error, "token fetch failed");
+        }
+        result
+    }
+
+    /// Starts a background task that refreshes cached tokens shortly before they expire, so
+    /// callers never wait on a token round-trip. Only scopes that have been asked for at
+    /// least once are kept warm.
+    ///
+    /// The task stops once every clone of the cache, and so every client using it, is dropped.
+    /// Starting a new refresher stops the previous one.
+    pub fn spawn_refresher(&self, options: RefreshOptions) {
+        let (stop, stopped) = oneshot::channel();
+        *self.inner.stop_refresher.lock().unwrap() = Some(stop);
+        tokio::spawn(refresh_loop(Arc::downgrade(&self.inner), options, stopped));
+    }
+
+    /// Refreshes every token that is due and returns how long to wait before looking again.
+    async fn refresh_due(&self, options: &RefreshOptions, schedule: &mut Schedule) -> Duration {
+        let slots: Vec<(Vec<String>, Arc<Slot>)> = {
+            let slots = self.inner.slots.lock().unwrap();
+            slots
+                .iter()
+                .map(|(key, slot)| (key.clone(), slot.clone()))
+                .collect()
+        };
+
+        let mut wait = REFRESHER_IDLE_POLL;
+        for (key, slot) in slots {
+            let expires_at = {
+                let state = slot.state.lock().await;
+                state.token.as_ref().and_then(|token| token.expires_at)
+            };
+            let expires_at = match expires_at {
+                Some(expires_at) => expires_at,
+                None => continue,
+            };
+            let now = SystemTime::now();
+            let mut due_at = expires_at.checked_sub(options.lead_time).unwrap_or(now);
+            if let Some(not_before) = schedule.not_before.get(&key) {
+                due_at = due_at.max(*not_before);
+            }
+            if let Some(backoff) = schedule.backoffs.get(&key) {
+                due_at = due_at.max(backoff.retry_at);
+            }
+            if due_at > now {
+                wait = wait.min(due_at.duration_since(now).unwrap_or_default());
+                continue;
+            }
+
+            // Fetched without holding the slot, so callers keep getting the cached token, which is
+            // still fresh, until the new one is swapped in.
+            let scope = key.join(" ");
+            match self.fetch(&scope).await {
+                Ok(token) => {
+                    schedule.backoffs.remove(&key);
+                    if let Some(expires_at) = token.expires_at {
+                        let now = SystemTime::now();
+                        let delay = refresh_delay(expires_at, options.lead_time, now);
+                        schedule.not_before.insert(key, now + delay);
+                        wait = wait.min(delay);
+                    }
+                    store(&mut *slot.state.lock().await, token);
+                }
+                Err(error) => {
+                    let delay = match schedule.backoffs.get(&key) {
+                        Some(backoff) => (backoff.delay * 2).min(options.max_backoff),
+                        None => options.initial_backoff,
+                    };
+                    schedule.backoffs.insert(
+                        key,
+                        Backoff {
+                            delay,
+                            retry_at: SystemTime::now() + delay,
+                        },
+                    );
+                    if let Some(on_failure) = &options.on_failure {
+                        on_failure(&scope, &error);
+                    }
+                    wait = wait.min(delay);
+                }
+            }
+        }
+        wait.max(options.initial_backoff)
+    }
+
+    /// Drops the cached token for `scope` if it is still `rejected`, so the next `get` fetches
+    /// a new one. Tokens already replaced by another caller are left alone.
+    pub async fn invalidate(&self, scope: &str, rejected: &str) {
+        let slot = self.slot(scope);
+        let mut state = slot.state.lock().await;
+        if state.token.as_ref().map(|token| token.token == rejected) == Some(true) {
+            state.token = None;
+        }
+    }
+
+    pub fn quota_project_id(&self) -> Option<&str> {
+        self.inner.source.quota_project_id()
+    }
+
+    fn slot(&self, scope: &str) -> Arc<Slot> {
+        let mut slots = self.inner.slots.lock().unwrap();
+        slots.entry(scope_key(scope)).or_default().clone()
+    }
+}
+
+async fn refresh_loop(
+    inner: Weak<Inner>,
+    options: RefreshOptions,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let mut schedule = Schedule::default();
+    loop {
+        // Only hold on to the cache while refreshing, so dropping the last client ends the task.
+        let wait = match inner.upgrade() {
+            Some(inner) => {
+                TokenCache { inner }
+                    .refresh_due(&options, &mut schedule)
+                    .await
+            }
+            None => return,
+        };
+        tokio::select! {
+            _ = tokio::time::delay_for(wait) => {}
+            _ = &mut stopped => return,
+        }
+    }
+}
+
+// What the refresher remembers between passes, per scope set.
+#[derive(Default)]
+struct Schedule {
+    // When a token the refresher fetched itself is next due, which for tokens that arrive
+    // with less than the lead time left comes later than the lead time alone says.
+    not_before: HashMap<Vec<String>, SystemTime>,
+    backoffs: HashMap<Vec<String>, Backoff>,
+}
+
+struct Backoff {
+    delay: Duration,
+    retry_at: SystemTime,
+}
+
+// Waits until the lead time before expiry or, for a token with less life left than that, half
+// of what remains, and never less than `MIN_REFRESH_INTERVAL`.
+fn refresh_delay(expires_at: SystemTime, lead_time: Duration, now: SystemTime) -> Duration {
+    let remaining = expires_at.duration_since(now).unwrap_or_default();
+    remaining
+        .checked_sub(lead_time)
+        .unwrap_or(remaining / 2)
+        .max(MIN_REFRESH_INTERVAL)
+}
+
+type FailureHook = Arc<dyn Fn(&str, &Error) + Send + Sync>;
+
+/// Settings for `TokenCache::spawn_refresher`.
+#[derive(Clone)]
+pub struct RefreshOptions {
+    lead_time: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    on_failure: Option<FailureHook>,
+}
+
+impl RefreshOptions {
+    /// How long before expiry tokens are refreshed. This should exceed the margin `get` applies,
+    /// otherwise callers may still find a stale token and fetch it themselves.
+    pub fn lead_time(mut self, lead_time: Duration) -> Self {
+        self.lead_time = lead_time;
+        self
+    }
+
+    /// The first delay before retrying a failed refresh; it doubles with each further failure.
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Called with the scope and error whenever a background refresh fails.
+    pub fn on_failure<F: Fn(&str, &Error) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.on_failure = Some(Arc::new(f));
+        self
+    }
+}
+
+impl Default for RefreshOptions {
+    fn default() -> Self {
+        RefreshOptions {
+            lead_time: Duration::from_secs(7 * 60),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            on_failure: None,
+        }
+    }
+}
+
+impl fmt::Debug for RefreshOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshOptions")
+            .field("lead_time", &self.lead_time)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .finish()
+    }
+}
+
+fn store(state: &mut SlotState, token: AccessToken) -> String {
+    let value = token.token.clone();
+    state.token = Some(token);
+    state.last_error = None;
+    value
+}
+
+fn scope_key(scope: &str) -> Vec<String> {
+    let mut scopes: Vec<String> = scope.split_whitespace().map(str::to_owned).collect();
+    scopes.sort();
+    scopes.dedup();
+    scopes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::from_fn;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    fn counting_cache(fetches: Arc<AtomicUsize>) -> TokenCache {
+        TokenCache::new(from_fn(move |scope| {
+            let count = fetches.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::delay_for(Duration::from_millis(20)).await;
+                Ok(AccessToken::new(
+                    format!("{}-{}", scope, count),
+                    Duration::from_secs(3600),
+                ))
+            }
+        }))
+    }
+
+    #[tokio::test]
+    async fn it_only_refetches_stale_tokens() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let cache = counting_cache(fetches.clone());
+
+        assert_eq!(cache.get("scope").await.unwrap(), "scope-0");
+        assert_eq!(cache.get("scope").await.unwrap(), "scope-0");
+
+        cache.invalidate("scope", "some-other-token").await;
+        assert_eq!(cache.get("scope").await.unwrap(), "scope-0");
+
+        cache.invalidate("scope", "scope-0").await;
+        assert_eq!(cache.get("scope").await.unwrap(), "scope-1");
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn it_keys_tokens_by_scope_set() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let cache = counting_cache(fetches.clone());
+
+        let both = cache.get("a b").await.unwrap();
+        assert_eq!(cache.get("b a").await.unwrap(), both);
+        assert_ne!(cache.get("a").await.unwrap(), both);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn it_refreshes_in_the_background_until_the_cache_is_dropped() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let cache = counting_cache(fetches.clone());
+        cache.get("scope").await.unwrap();
+
+        cache.spawn_refresher(
+            RefreshOptions::default()
+                .lead_time(Duration::from_secs(3600))
+                .initial_backoff(Duration::from_millis(10)),
+        );
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert!(fetches.load(Ordering::SeqCst) > 1);
+        assert_ne!(cache.get("scope").await.unwrap(), "scope-0");
+
+        drop(cache);
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        let after_drop = fetches.load(Ordering::SeqCst);
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), after_drop);
+    }
+
+    #[tokio::test]
+    async fn it_serves_the_cached_token_while_a_refresh_is_in_flight() {
+        let (started, mut refreshing) = tokio::sync::mpsc::unbounded_channel();
+        let release = Arc::new(Mutex::new(()));
+        let held = release.clone().lock_owned().await;
+        let counter = Arc::new(AtomicUsize::new(0));
+        let cache = TokenCache::new(from_fn(move |_scope| {
+            let count = counter.fetch_add(1, Ordering::SeqCst);
+            let started = started.clone();
+            let release = release.clone();
+            async move {
+                if count > 0 {
+                    started.send(()).unwrap();
+                    let _ = release.lock().await;
+                }
+                Ok(AccessToken::new(
+                    format!("token-{}", count),
+                    Duration::from_secs(3600),
+                ))
+            }
+        }));
+        cache.get("scope").await.unwrap();
+
+        cache.spawn_refresher(RefreshOptions::default().lead_time(Duration::from_secs(3600)));
+        refreshing.recv().await.unwrap();
+        let cached = tokio::time::timeout(Duration::from_secs(5), cache.get("scope"));
+        assert_eq!(cached.await.unwrap().unwrap(), "token-0");
+
+        drop(held);
+        let refreshed = async {
+            while cache.get("scope").await.unwrap() != "token-1" {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), refreshed)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_does_not_hammer_sources_that_hand_out_short_lived_tokens() {
+        let (fetched, mut fetches) = tokio::sync::mpsc::unbounded_channel();
+        let cache = TokenCache::new(from_fn(move |_scope| {
+            fetched.send(()).unwrap();
+            // Like the metadata server, which serves its cached token until about five
+            // minutes are left, well inside the default lead time.
+            async {
+                Ok(AccessToken::new(
+                    "token".to_owned(),
+                    Duration::from_secs(300),
+                ))
+            }
+        }));
+        cache.get("scope").await.unwrap();
+        fetches.recv().await.unwrap();
+
+        cache.spawn_refresher(RefreshOptions::default().initial_backoff(Duration::from_millis(10)));
+        fetches.recv().await.unwrap();
+        let again = tokio::time::timeout(Duration::from_millis(200), fetches.recv());
+        assert!(again.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_reports_refresh_failures_and_backs_off() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let cache = TokenCache::new(from_fn(move |_scope| {
+            let count = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if count == 0 {
+                    Ok(AccessToken::new(
+                        "token".to_owned(),
+                        Duration::from_secs(3600),
+                    ))
+                } else {
+                    Err(Error::token("token endpoint unavailable"))
+                }
+            }
+        }));
+        cache.get("scope").await.unwrap();
+
+        let failures = Arc::new(SyncMutex::new(Vec::new()));
+        let reported = failures.clone();
+        cache.spawn_refresher(
+            RefreshOptions::default()
+                .lead_time(Duration::from_secs(3600))
+                .initial_backoff(Duration::from_millis(10))
+                .max_backoff(Duration::from_millis(40))
+                .on_failure(move |scope, error| {
+                    reported
+                        .lock()
+                        .unwrap()
+                        .push(format!("{}: {}", scope, error));
+                }),
+        );
+        tokio::time::delay_for(Duration::from_millis(150)).await;
+
+        let failures = failures.lock().unwrap();
+        assert!(!failures.is_empty());
+        assert!(failures[0].starts_with("scope: "));
+        assert!(fetches.load(Ordering::SeqCst) < 10);
+    }
+
+    #[tokio::test]
+    async fn it_reports_fetches_to_the_metrics_it_was_built_with() {
+        use crate::connection::StaticToken;
+        use crate::metrics::InMemoryMetrics;
+
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let cache = TokenCache::with_metrics(StaticToken::new("token"), metrics.clone());
+        cache.get("scope").await.unwrap();
+        cache.clone().get("scope").await.unwrap();
+        assert!(metrics
+            .render()
+            .contains("google_token_fetches_total{outcome=\"ok\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn it_hands_waiters_the_error_of_the_fetch_they_waited_on() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let cache = TokenCache::new(from_fn(move |_scope| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async {
+                tokio::task::yield_now().await;
+                Err(Error::from(tonic::Status::unavailable(
+                    "token endpoint overloaded",
+                )))
+            }
+        }));
+
+        let (first, second) = tokio::join!(cache.get("scope"), cache.get("scope"));
+        for error in &[first.unwrap_err(), second.unwrap_err()] {
+            assert!(error.is_retryable());
+            assert_eq!(error.code(), Some(tonic::Code::Unavailable));
+            assert_eq!(error.to_string(), "Unavailable: token endpoint overloaded");
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_shares_one_fetch_between_concurrent_callers_and_clones() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let cache = counting_cache(fetches.clone());
+        let clone = cache.clone();
+
+        let (first, second, third) =
+            tokio::join!(cache.get("scope"), clone.get("scope"), cache.get("scope"));
+        assert_eq!(first.unwrap(), "scope-0");
+        assert_eq!(second.unwrap(), "scope-0");
+        assert_eq!(third.unwrap(), "scope-0");
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,101 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use super::AccessToken;
+use crate::error::Error;
+
+/// Anything that can mint bearer tokens for Google APIs.
+///
+/// Implementations only fetch tokens; caching and refreshing is left to `TokenCache`.
+#[async_trait]
+pub trait TokenSource: Send + Sync {
+    async fn fetch_token(&self, scope: &str) -> Result<AccessToken, Error>;
+
+    /// The project billed for quota, from the credentials file's `quota_project_id`.
+    fn quota_project_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[async_trait]
+impl<T: TokenSource + ?Sized> TokenSource for Box<T> {
+    async fn fetch_token(&self, scope: &str) -> Result<AccessToken, Error> {
+        (**self).fetch_token(scope).await
+    }
+
+    fn quota_project_id(&self) -> Option<&str> {
+        (**self).quota_project_id()
+    }
+}
+
+#[async_trait]
+impl<T: TokenSource + ?Sized> TokenSource for Arc<T> {
+    async fn fetch_token(&self, scope: &str) -> Result<AccessToken, Error> {
+        (**self).fetch_token(scope).await
+    }
+
+    fn quota_project_id(&self) -> Option<&str> {
+        (**self).quota_project_id()
+    }
+}
+
+/// A bearer token minted elsewhere, handed out as-is regardless of scope.
+#[derive(Clone, Debug)]
+pub struct StaticToken {
+    token: AccessToken,
+}
+
+impl StaticToken {
+    pub fn new<S: Into<String>>(token: S) -> Self {
+        StaticToken {
+            token: AccessToken {
+                token: token.into(),
+                expires_at: None,
+            },
+        }
+    }
+
+    pub fn with_expiry<S: Into<String>>(token: S, expires_at: SystemTime) -> Self {
+        StaticToken {
+            token: AccessToken {
+                token: token.into(),
+                expires_at: Some(expires_at),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl TokenSource for StaticToken {
+    async fn fetch_token(&self, _scope: &str) -> Result<AccessToken, Error> {
+        Ok(self.token.clone())
+    }
+}
+
+/// Wraps a user-provided closure as a token source. See `from_fn`.
+pub struct FnTokenSource<F> {
+    f: F,
+}
+
+/// Builds a token source out of a closure that is handed the requested scope.
+pub fn from_fn<F, Fut>(f: F) -> FnTokenSource<F>
+where
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<AccessToken, Error>> + Send,
+{
+    FnTokenSource { f }
+}
+
+#[async_trait]
+impl<F, Fut> TokenSource for FnTokenSource<F>
+where
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<AccessToken, Error>> + Send,
+{
+    async fn fetch_token(&self, scope: &str) -> Result<AccessToken, Error> {
+        (self.f)(scope.to_owned()).await
+    }
+}
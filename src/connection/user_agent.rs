@@ -0,0 +1,86 @@
+use std::task::{Context, Poll};
+
+use http::header::{HeaderValue, USER_AGENT};
+use tonic::client::GrpcService;
+use tower_service::Service;
+
+/// Sends a `user-agent` header with every request made through `inner`. tonic strips the
+/// header from request metadata, so it has to be added beneath the generated client:
+///
+/// ```ignore
+/// let user_agent = HeaderValue::from_static("my-service/1.0");
+/// let channel = UserAgent::new(endpoint.connect().await?, Some(user_agent));
+/// let mut client = FirestoreClient::new(channel);
+/// ```
+#[derive(Clone, Debug)]
+pub struct UserAgent<S> {
+    inner: S,
+    user_agent: Option<HeaderValue>,
+}
+
+impl<S> UserAgent<S> {
+    pub fn new(inner: S, user_agent: Option<HeaderValue>) -> Self {
+        UserAgent { inner, user_agent }
+    }
+}
+
+impl<S: GrpcService<B>, B> Service<http::Request<B>> for UserAgent<S> {
+    type Response = http::Response<S::ResponseBody>;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        GrpcService::poll_ready(&mut self.inner, cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> S::Future {
+        if let Some(user_agent) = &self.user_agent {
+            request.headers_mut().insert(USER_AGENT, user_agent.clone());
+        }
+        GrpcService::call(&mut self.inner, request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::BoxError;
+    use std::future::Future;
+    use std::pin::Pin;
+    use tonic::body::BoxBody;
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<http::Request<()>> for Echo {
+        type Response = http::Response<BoxBody>;
+        type Error = BoxError;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<()>) -> Self::Future {
+            let mut response = http::Response::new(BoxBody::empty());
+            *response.headers_mut() = request.headers().clone();
+            Box::pin(async { Ok(response) })
+        }
+    }
+
+    #[tokio::test]
+    async fn it_sends_the_user_agent_beneath_the_client() {
+        let user_agent = HeaderValue::from_static("my-service/1.0");
+        let mut service = UserAgent::new(Echo, Some(user_agent));
+        let response = Service::call(&mut service, http::Request::new(()))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[USER_AGENT], "my-service/1.0");
+
+        let mut service = UserAgent::new(Echo, None);
+        let response = Service::call(&mut service, http::Request::new(()))
+            .await
+            .unwrap();
+        assert!(response.headers().get(USER_AGENT).is_none());
+    }
+}
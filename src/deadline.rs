@@ -0,0 +1,172 @@
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http::header::HeaderValue;
+use tonic::client::GrpcService;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::{IntoRequest, Request};
+use tower_service::Service;
+
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+// The wire format allows at most eight digits, so each unit covers values below this.
+const MAX_TIMEOUT_VALUE: u128 = 100_000_000;
+
+/// Gives a `Firestore` call `timeout` to complete, across any retries. The server is told
+/// through the `grpc-timeout` header and stops working on the call once it expires. Raw
+/// clients need their channel wrapped in a `GrpcTimeout` instead.
+pub fn with_deadline<X, R: IntoRequest<X>>(request: R, timeout: Duration) -> Request<X> {
+    let mut request = request.into_request();
+    set_timeout(request.metadata_mut(), timeout);
+    request
+}
+
+pub(crate) fn set_timeout(metadata: &mut MetadataMap, timeout: Duration) {
+    let value = MetadataValue::from_str(&encode(timeout)).expect("Timeouts are ASCII");
+    metadata.insert(GRPC_TIMEOUT, value);
+}
+
+pub(crate) fn timeout_of(metadata: &MetadataMap) -> Option<Duration> {
+    decode(metadata.get(GRPC_TIMEOUT)?.to_str().ok()?)
+}
+
+/// Sends `grpc-timeout` with every request made through `inner`. tonic strips the header from
+/// request metadata, so it has to be added beneath the generated client:
+///
+/// ```ignore
+/// let channel = GrpcTimeout::new(endpoint.connect().await?, Some(Duration::from_secs(5)));
+/// let mut client = FirestoreClient::new(channel);
+/// ```
+#[derive(Clone, Debug)]
+pub struct GrpcTimeout<S> {
+    inner: S,
+    timeout: Option<HeaderValue>,
+}
+
+impl<S> GrpcTimeout<S> {
+    pub fn new(inner: S, timeout: Option<Duration>) -> Self {
+        let timeout = timeout
+            .map(|timeout| HeaderValue::from_str(&encode(timeout)).expect("Timeouts are ASCII"));
+        GrpcTimeout { inner, timeout }
+    }
+}
+
+impl<S: GrpcService<B>, B> Service<http::Request<B>> for GrpcTimeout<S> {
+    type Response = http::Response<S::ResponseBody>;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        GrpcService::poll_ready(&mut self.inner, cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> S::Future {
+        if let Some(timeout) = &self.timeout {
+            request.headers_mut().insert(GRPC_TIMEOUT, timeout.clone());
+        }
+        GrpcService::call(&mut self.inner, request)
+    }
+}
+
+fn encode(timeout: Duration) -> String {
+    let nanos = timeout.as_nanos();
+    let units: [(&str, u128); 6] = [
+        ("n", 1),
+        ("u", 1_000),
+        ("m", 1_000_000),
+        ("S", 1_000_000_000),
+        ("M", 60_000_000_000),
+        ("H", 3_600_000_000_000),
+    ];
+    for (unit, per) in units.iter() {
+        // Rounded up so a short timeout never reaches the server as zero.
+        let value = (nanos + per - 1) / per;
+        if value < MAX_TIMEOUT_VALUE {
+            return format!("{}{}", value, unit);
+        }
+    }
+    format!("{}H", MAX_TIMEOUT_VALUE - 1)
+}
+
+fn decode(value: &str) -> Option<Duration> {
+    if value.len() < 2 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    // At most eight digits, which also keeps the minutes and hours below from overflowing.
+    if amount.len() > 8 || !amount.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "n" => Some(Duration::from_nanos(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "S" => Some(Duration::from_secs(amount)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_grpc_timeouts() {
+        assert_eq!(encode(Duration::from_millis(1500)), "1500000u");
+        assert_eq!(encode(Duration::from_secs(3600)), "3600000m");
+        assert_eq!(encode(Duration::from_nanos(1)), "1n");
+
+        let request = with_deadline((), Duration::from_secs(2));
+        assert_eq!(timeout_of(request.metadata()), Some(Duration::from_secs(2)));
+        assert_eq!(decode("10M"), Some(Duration::from_secs(600)));
+        assert_eq!(decode("soon"), None);
+        assert_eq!(
+            decode("99999999H"),
+            Some(Duration::from_secs(99_999_999 * 3600))
+        );
+        assert_eq!(decode("100000000H"), None);
+        assert_eq!(decode("18446744073709551615H"), None);
+        assert_eq!(decode("+5S"), None);
+    }
+
+    #[tokio::test]
+    async fn it_sends_the_timeout_beneath_the_client() {
+        use std::future::Future;
+        use std::pin::Pin;
+
+        #[derive(Clone)]
+        struct Echo;
+
+        impl Service<http::Request<()>> for Echo {
+            type Response = http::Response<tonic::body::BoxBody>;
+            type Error = crate::BoxError;
+            type Future =
+                Pin<Box<dyn Future<Output = Result<Self::Response, crate::BoxError>> + Send>>;
+
+            fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), crate::BoxError>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, request: http::Request<()>) -> Self::Future {
+                let mut response = http::Response::new(tonic::body::BoxBody::empty());
+                *response.headers_mut() = request.headers().clone();
+                Box::pin(async { Ok(response) })
+            }
+        }
+
+        let mut service = GrpcTimeout::new(Echo, Some(Duration::from_millis(1500)));
+        let response = Service::call(&mut service, http::Request::new(()))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[GRPC_TIMEOUT], "1500000u");
+
+        let mut service = GrpcTimeout::new(Echo, None);
+        let response = Service::call(&mut service, http::Request::new(()))
+            .await
+            .unwrap();
+        assert!(response.headers().get(GRPC_TIMEOUT).is_none());
+    }
+}
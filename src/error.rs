@@ -0,0 +1,348 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::Code;
+
+use crate::google::rpc::{BadRequest, ErrorInfo};
+
+mod details;
+
+pub use details::ErrorDetail;
+
+pub type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Everything that can go wrong talking to Google APIs through this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// Credentials could not be found, read or parsed. `origin` names where they came from.
+    Credentials { origin: String, source: BoxError },
+    /// A token source failed to produce an access token.
+    Token(BoxError),
+    /// The connection to the endpoint could not be established.
+    Transport(tonic::transport::Error),
+    /// The connection was not established within the endpoint's connect timeout.
+    ConnectTimeout(std::time::Duration),
+    /// The server answered with a non-OK status. `details` are decoded from the
+    /// `grpc-status-details-bin` trailer, if any.
+    Rpc {
+        code: Code,
+        message: String,
+        details: Vec<ErrorDetail>,
+    },
+    /// A document or collection path could not be made sense of.
+    InvalidPath(String),
+    /// A value could not be converted to a Firestore value, e.g. an integer out of range.
+    InvalidValue(String),
+    /// A credentials file or response body could not be (de)serialized.
+    Serialization(serde_json::Error),
+    /// One failure handed to several callers, such as a token fetch that concurrent calls all
+    /// waited on. It reports the code, details and retryability of the error it wraps.
+    Shared(Arc<Error>),
+}
+
+impl Error {
+    pub fn credentials<O: Into<String>, E: Into<BoxError>>(origin: O, source: E) -> Self {
+        Error::Credentials {
+            origin: origin.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn token<E: Into<BoxError>>(source: E) -> Self {
+        Error::Token(source.into())
+    }
+
+    /// The gRPC status code, for errors returned by the server.
+    pub fn code(&self) -> Option<Code> {
+        match self {
+            Error::Rpc { code, .. } => Some(*code),
+            Error::Shared(error) => error.code(),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.code() == Some(Code::NotFound)
+    }
+
+    pub fn is_already_exists(&self) -> bool {
+        self.code() == Some(Code::AlreadyExists)
+    }
+
+    pub fn is_unauthenticated(&self) -> bool {
+        self.code() == Some(Code::Unauthenticated)
+    }
+
+    /// Whether the call ran out of time, whether the server or this client noticed first.
+    pub fn is_deadline_exceeded(&self) -> bool {
+        self.code() == Some(Code::DeadlineExceeded)
+    }
+
+    pub(crate) fn deadline_exceeded(timeout: Duration) -> Self {
+        Error::Rpc {
+            code: Code::DeadlineExceeded,
+            message: format!("Deadline of {:?} exceeded", timeout),
+            details: Vec::new(),
+        }
+    }
+
+    /// Whether the failure is transient, so the same call may succeed if made again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Transport(_) | Error::ConnectTimeout(_) => true,
+            Error::Rpc { code, .. } => match code {
+                Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted => true,
+                _ => false,
+            },
+            Error::Shared(error) => error.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// The structured details the server attached to a failed call.
+    pub fn details(&self) -> &[ErrorDetail] {
+        match self {
+            Error::Rpc { details, .. } => details,
+            Error::Shared(error) => error.details(),
+            _ => &[],
+        }
+    }
+
+    /// The reason code and domain of the failure, e.g. `SERVICE_DISABLED`.
+    pub fn error_info(&self) -> Option<&ErrorInfo> {
+        self.details().iter().find_map(|detail| match detail {
+            ErrorDetail::ErrorInfo(info) => Some(info),
+            _ => None,
+        })
+    }
+
+    /// Which fields of the request were rejected, and why.
+    pub fn bad_request(&self) -> Option<&BadRequest> {
+        self.details().iter().find_map(|detail| match detail {
+            ErrorDetail::BadRequest(bad_request) => Some(bad_request),
+            _ => None,
+        })
+    }
+
+    /// How long the server asked clients to wait before retrying, from a `RetryInfo` detail.
+    pub fn retry_delay(&self) -> Option<Duration> {
+        let delay = self.details().iter().find_map(|detail| match detail {
+            ErrorDetail::RetryInfo(info) => info.retry_delay.as_ref(),
+            _ => None,
+        })?;
+        Some(Duration::new(
+            delay.seconds.max(0) as u64,
+            delay.nanos.max(0) as u32,
+        ))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Credentials { origin, source } => {
+                write!(f, "Unable to load credentials from {}: {}", origin, source)
+            }
+            Error::Token(source) => write!(f, "Unable to fetch an access token: {}", source),
+            Error::Transport(source) => write!(f, "Transport error: {}", source),
+            Error::ConnectTimeout(limit) => {
+                write!(f, "Timed out connecting after {:?}", limit)
+            }
+            Error::Rpc { code, message, .. } => {
+                write!(f, "{:?}: {}", code, message)?;
+                if let Some(info) = self.error_info() {
+                    write!(f, " [{}]", info.reason)?;
+                }
+                for violation in self
+                    .bad_request()
+                    .map_or(&[][..], |b| &b.field_violations[..])
+                {
+                    write!(f, "; {}: {}", violation.field, violation.description)?;
+                }
+                Ok(())
+            }
+            Error::InvalidPath(path) => write!(f, "Invalid document path `{}`", path),
+            Error::InvalidValue(reason) => write!(f, "Invalid document value: {}", reason),
+            Error::Serialization(source) => write!(f, "Serialization error: {}", source),
+            Error::Shared(error) => fmt::Display::fmt(error, f),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Credentials { source, .. } => Some(source.as_ref()),
+            Error::Token(source) => Some(source.as_ref()),
+            Error::Transport(source) => Some(source),
+            Error::Serialization(source) => Some(source),
+            Error::Shared(error) => error.source(),
+            Error::Rpc { .. }
+            | Error::ConnectTimeout(_)
+            | Error::InvalidPath(_)
+            | Error::InvalidValue(_) => None,
+        }
+    }
+}
+
+impl From<tonic::Status> for Error {
+    fn from(status: tonic::Status) -> Self {
+        Error::Rpc {
+            code: status.code(),
+            message: status.message().to_owned(),
+            details: details::decode(status.details()),
+        }
+    }
+}
+
+impl From<tonic::transport::Error> for Error {
+    fn from(error: tonic::transport::Error) -> Self {
+        Error::Transport(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Serialization(error)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Token(Box::new(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_classifies_rpc_errors() {
+        let not_found = Error::from(tonic::Status::new(Code::NotFound, "no such document"));
+        assert!(not_found.is_not_found());
+        assert!(!not_found.is_retryable());
+        assert_eq!(not_found.to_string(), "NotFound: no such document");
+
+        let unavailable = Error::from(tonic::Status::new(Code::Unavailable, "try again"));
+        assert!(unavailable.is_retryable());
+        assert!(!unavailable.is_already_exists());
+    }
+
+    #[test]
+    fn it_chains_sources() {
+        let error = Error::credentials("the metadata server", "connection refused");
+        assert_eq!(
+            error.to_string(),
+            "Unable to load credentials from the metadata server: connection refused"
+        );
+        assert_eq!(error.source().unwrap().to_string(), "connection refused");
+    }
+
+    #[test]
+    fn it_shows_reasons_and_field_violations() {
+        use crate::google::rpc::bad_request::FieldViolation;
+        use crate::google::rpc::RetryInfo;
+
+        let error = Error::Rpc {
+            code: Code::InvalidArgument,
+            message: "invalid document".to_owned(),
+            details: vec![
+                ErrorDetail::ErrorInfo(ErrorInfo {
+                    reason: "FIELD_TOO_DEEP".to_owned(),
+                    domain: "firestore.googleapis.com".to_owned(),
+                    metadata: Default::default(),
+                }),
+                ErrorDetail::BadRequest(BadRequest {
+                    field_violations: vec![FieldViolation {
+                        field: "document.fields".to_owned(),
+                        description: "nested too deeply".to_owned(),
+                    }],
+                }),
+                ErrorDetail::RetryInfo(RetryInfo {
+                    retry_delay: Some(prost_types::Duration {
+                        seconds: 2,
+                        nanos: 500_000_000,
+                    }),
+                }),
+            ],
+        };
+        assert_eq!(
+            error.to_string(),
+            "InvalidArgument: invalid document [FIELD_TOO_DEEP]; document.fields: nested too deeply"
+        );
+        assert_eq!(error.retry_delay(), Some(Duration::from_millis(2500)));
+    }
+
+    #[tokio::test]
+    async fn it_decodes_details_from_the_wire() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        use prost::Message;
+        use tonic::body::BoxBody;
+
+        use crate::google::firestore::v1::firestore_client::FirestoreClient;
+        use crate::google::firestore::v1::GetDocumentRequest;
+        use crate::google::rpc::{RetryInfo, Status};
+
+        // Answers every call with a trailers-only response, as a server rejecting it would.
+        #[derive(Clone)]
+        struct Rejecting(String);
+
+        impl tower_service::Service<http::Request<BoxBody>> for Rejecting {
+            type Response = http::Response<BoxBody>;
+            type Error = BoxError;
+            type Future =
+                Pin<Box<dyn Future<Output = Result<http::Response<BoxBody>, BoxError>> + Send>>;
+
+            fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, _: http::Request<BoxBody>) -> Self::Future {
+                let response = http::Response::builder()
+                    .header("content-type", "application/grpc")
+                    .header("grpc-status", "14")
+                    .header("grpc-message", "try later")
+                    .header("grpc-status-details-bin", self.0.as_str())
+                    .body(BoxBody::empty())
+                    .unwrap();
+                Box::pin(async { Ok(response) })
+            }
+        }
+
+        let mut retry_info = Vec::new();
+        RetryInfo {
+            retry_delay: Some(prost_types::Duration {
+                seconds: 3,
+                nanos: 0,
+            }),
+        }
+        .encode(&mut retry_info)
+        .unwrap();
+        let mut trailer = Vec::new();
+        Status {
+            code: 14,
+            message: "try later".to_owned(),
+            details: vec![prost_types::Any {
+                type_url: "type.googleapis.com/google.rpc.RetryInfo".to_owned(),
+                value: retry_info,
+            }],
+        }
+        .encode(&mut trailer)
+        .unwrap();
+
+        let header = base64::encode(&trailer);
+        let status = FirestoreClient::new(Rejecting(header))
+            .get_document(GetDocumentRequest::default())
+            .await
+            .unwrap_err();
+        let error = Error::from(status);
+        assert!(error.is_retryable());
+        assert_eq!(error.retry_delay(), Some(Duration::from_secs(3)));
+    }
+}
@@ -0,0 +1,138 @@
+use prost::Message;
+
+use crate::google::rpc::{
+    BadRequest, DebugInfo, ErrorInfo, Help, LocalizedMessage, PreconditionFailure, QuotaFailure,
+    RequestInfo, ResourceInfo, RetryInfo, Status,
+};
+
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/google.rpc.";
+
+/// One entry of the `details` a server attaches to a failed call.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorDetail {
+    ErrorInfo(ErrorInfo),
+    RetryInfo(RetryInfo),
+    DebugInfo(DebugInfo),
+    QuotaFailure(QuotaFailure),
+    PreconditionFailure(PreconditionFailure),
+    BadRequest(BadRequest),
+    RequestInfo(RequestInfo),
+    ResourceInfo(ResourceInfo),
+    Help(Help),
+    LocalizedMessage(LocalizedMessage),
+    /// A detail of a type not known here, or one that failed to decode, left as sent.
+    Other(prost_types::Any),
+}
+
+impl ErrorDetail {
+    fn decode(any: prost_types::Any) -> Self {
+        if !any.type_url.starts_with(TYPE_URL_PREFIX) {
+            return ErrorDetail::Other(any);
+        }
+        let name = &any.type_url[TYPE_URL_PREFIX.len()..];
+        let value = &any.value[..];
+        let detail = match name {
+            "ErrorInfo" => ErrorInfo::decode(value).map(ErrorDetail::ErrorInfo),
+            "RetryInfo" => RetryInfo::decode(value).map(ErrorDetail::RetryInfo),
+            "DebugInfo" => DebugInfo::decode(value).map(ErrorDetail::DebugInfo),
+            "QuotaFailure" => QuotaFailure::decode(value).map(ErrorDetail::QuotaFailure),
+            "PreconditionFailure" => {
+                PreconditionFailure::decode(value).map(ErrorDetail::PreconditionFailure)
+            }
+            "BadRequest" => BadRequest::decode(value).map(ErrorDetail::BadRequest),
+            "RequestInfo" => RequestInfo::decode(value).map(ErrorDetail::RequestInfo),
+            "ResourceInfo" => ResourceInfo::decode(value).map(ErrorDetail::ResourceInfo),
+            "Help" => Help::decode(value).map(ErrorDetail::Help),
+            "LocalizedMessage" => {
+                LocalizedMessage::decode(value).map(ErrorDetail::LocalizedMessage)
+            }
+            _ => return ErrorDetail::Other(any),
+        };
+        detail.unwrap_or(ErrorDetail::Other(any))
+    }
+}
+
+/// Decodes the `grpc-status-details-bin` trailer, a base64 serialized `google.rpc.Status`.
+///
+/// tonic hands the header over as it came off the wire, and servers differ on whether they pad
+/// it, so both forms are accepted.
+pub(crate) fn decode(trailer: &[u8]) -> Vec<ErrorDetail> {
+    if trailer.is_empty() {
+        return Vec::new();
+    }
+    let unpadded = match trailer.iter().rposition(|&byte| byte != b'=') {
+        Some(end) => &trailer[..=end],
+        None => return Vec::new(),
+    };
+    let bytes = match base64::decode_config(unpadded, base64::STANDARD_NO_PAD) {
+        Ok(bytes) => bytes,
+        Err(_) => return Vec::new(),
+    };
+    Status::decode(&bytes[..])
+        .map(|status| {
+            status
+                .details
+                .into_iter()
+                .map(ErrorDetail::decode)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::google::rpc::bad_request::FieldViolation;
+
+    fn any<M: Message>(name: &str, message: M) -> prost_types::Any {
+        let mut value = Vec::new();
+        message.encode(&mut value).unwrap();
+        prost_types::Any {
+            type_url: format!("{}{}", TYPE_URL_PREFIX, name),
+            value,
+        }
+    }
+
+    #[test]
+    fn it_decodes_known_details_and_keeps_the_rest() {
+        let bad_request = BadRequest {
+            field_violations: vec![FieldViolation {
+                field: "document.fields".to_owned(),
+                description: "too deep".to_owned(),
+            }],
+        };
+        let unknown = prost_types::Any {
+            type_url: "type.googleapis.com/example.Custom".to_owned(),
+            value: vec![1, 2, 3],
+        };
+        let mut trailer = Vec::new();
+        Status {
+            code: 3,
+            message: "invalid".to_owned(),
+            details: vec![
+                any("BadRequest", bad_request.clone()),
+                any(
+                    "ErrorInfo",
+                    ErrorInfo {
+                        reason: "FIELD_TOO_DEEP".to_owned(),
+                        domain: "firestore.googleapis.com".to_owned(),
+                        metadata: Default::default(),
+                    },
+                ),
+                unknown.clone(),
+            ],
+        }
+        .encode(&mut trailer)
+        .unwrap();
+
+        let details = decode(base64::encode(&trailer).as_bytes());
+        assert_eq!(details[0], ErrorDetail::BadRequest(bad_request));
+        assert!(
+            matches!(&details[1], ErrorDetail::ErrorInfo(info) if info.reason == "FIELD_TOO_DEEP")
+        );
+        assert_eq!(details[2], ErrorDetail::Other(unknown));
+        let unpadded = base64::encode_config(&trailer, base64::STANDARD_NO_PAD);
+        assert_eq!(decode(unpadded.as_bytes()), details);
+        assert!(decode(b"not a status").is_empty());
+    }
+}
@@ -1,59 +1,247 @@
 pub mod v1 {
-    use tonic::transport::Channel;
+    use futures_core::Stream;
+    use tokio::stream::StreamExt;
+    use tonic::codec::Streaming;
+    use tonic::metadata::MetadataMap;
+    use tracing_futures::Instrument;
 
-    use crate::connection::{Credentials, GrpcEndpoint};
+    use crate::connection::{
+        self, ApplicationCredentials, ChannelPool, GrpcEndpoint, Lease, PoolOptions, PooledChannel,
+        StaticToken, TokenCache, TokenSource,
+    };
+    use crate::deadline::{self, GrpcTimeout};
+    use crate::error::Error;
     use crate::google::firestore::v1::firestore_client::FirestoreClient;
     pub use crate::google::firestore::v1::{
-        CreateDocumentRequest, DeleteDocumentRequest, GetDocumentRequest,
+        BatchGetDocumentsRequest, BatchGetDocumentsResponse, CreateDocumentRequest,
+        DeleteDocumentRequest, GetDocumentRequest, ListenRequest, ListenResponse, RunQueryRequest,
+        RunQueryResponse,
     };
+    use crate::metrics::Metrics;
+    use crate::retry::{Idempotency, RetryPolicy};
+    use crate::trace::TraceContext;
 
     use crate::google::firestore::v1::value::ValueType;
-    use crate::google::firestore::v1::{Document as RPCDocument, UpdateDocumentRequest, Value};
-    use std::collections::HashMap;
-    use std::error::Error;
+    use crate::google::firestore::v1::{
+        ArrayValue, Document as RPCDocument, MapValue, UpdateDocumentRequest, Value,
+    };
+    pub use crate::google::r#type::LatLng;
+    use prost_types::Timestamp;
+    use std::collections::{BTreeMap, HashMap};
+    use std::convert::TryFrom;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant, SystemTime};
     use tonic::metadata::MetadataValue;
     use tonic::{Code, Response};
 
+    /// Audience for `SelfSignedJwt` tokens sent to Firestore.
+    pub const FIRESTORE_AUDIENCE: &str = "https://firestore.googleapis.com/";
+    pub const FIRESTORE_SCOPE: &str = "https://www.googleapis.com/auth/datastore";
+    // `gl-rust` is the Rust version, set by the build script. The crate reports its own version
+    // under its name rather than `gccl`, which belongs to Google's client libraries.
+    const API_CLIENT: &str = concat!(
+        "gl-rust/",
+        env!("RUSTC_VERSION"),
+        " ",
+        env!("CARGO_PKG_NAME"),
+        "/",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    /// A handle to Firestore. Clones share the same connections, tokens and settings, so a
+    /// clone can be handed to every task that makes calls.
+    #[derive(Clone)]
     pub struct Firestore {
-        service: FirestoreClient<Channel>,
-        credentials: Credentials,
+        channels: ChannelPool,
+        token: TokenCache,
+        retry: RetryPolicy,
+        deadline: Option<Duration>,
+        quota_project: Option<String>,
+        trace_context: Option<Arc<dyn Fn() -> Option<TraceContext> + Send + Sync>>,
+        metrics: Option<Arc<dyn Metrics>>,
         pub project_id: String,
     }
 
     impl Firestore {
-        pub async fn connect(credentials: Credentials) -> Result<Self, Box<dyn std::error::Error>> {
-            let service = FirestoreClient::connect(
-                GrpcEndpoint::new(&credentials, "firestore.googleapis.com").await?,
-            )
-            .await?;
+        /// Connects to Firestore, or to the emulator when `FIRESTORE_EMULATOR_HOST` is set, in
+        /// the project the credentials name. Takes what `Credentials::auto_acquire` finds as well
+        /// as any one kind of credentials, e.g. a service account key or an impersonated account.
+        pub async fn connect<C: Into<ApplicationCredentials>>(
+            credentials: C,
+        ) -> Result<Self, Error> {
+            let credentials = credentials.into();
+            Firestore::builder()
+                .project_id(credentials.project_id().await?)
+                .token_source(credentials)
+                .connect()
+                .await
+        }
 
-            Ok(Firestore {
-                service,
-                project_id: credentials.project_id.clone(),
-                credentials,
-            })
+        pub fn builder() -> FirestoreBuilder {
+            FirestoreBuilder::default()
+        }
+
+        /// Connects using any token source, e.g. a `StaticToken` or an `AuthorizedUser`.
+        pub async fn connect_with<P: Into<String>, T: TokenSource + 'static>(
+            endpoint: GrpcEndpoint,
+            project_id: P,
+            token_source: T,
+        ) -> Result<Self, Error> {
+            Firestore::connect_with_cache(endpoint, project_id, TokenCache::new(token_source)).await
+        }
+
+        /// Connects with a token cache that may be shared with other clients, so they all reuse
+        /// the same tokens.
+        pub async fn connect_with_cache<P: Into<String>>(
+            endpoint: GrpcEndpoint,
+            project_id: P,
+            token: TokenCache,
+        ) -> Result<Self, Error> {
+            let channels = ChannelPool::connect(endpoint, PoolOptions::default()).await?;
+            Ok(Firestore::connect_with_pool(channels, project_id, token))
+        }
+
+        /// Spreads calls over the connections of `channels`, which may be shared with other
+        /// clients.
+        pub fn connect_with_pool<P: Into<String>>(
+            channels: ChannelPool,
+            project_id: P,
+            token: TokenCache,
+        ) -> Self {
+            let quota_project = std::env::var("GOOGLE_CLOUD_QUOTA_PROJECT")
+                .ok()
+                .or_else(|| token.quota_project_id().map(str::to_owned));
+            Firestore {
+                channels,
+                project_id: project_id.into(),
+                quota_project,
+                trace_context: None,
+                metrics: None,
+                token,
+                retry: RetryPolicy::default(),
+                deadline: None,
+            }
+        }
+
+        /// Bills quota to `project` instead of the project in the `GOOGLE_CLOUD_QUOTA_PROJECT`
+        /// environment variable or the credentials' `quota_project_id`.
+        pub fn with_quota_project<P: Into<String>>(mut self, project: P) -> Self {
+            self.quota_project = Some(project.into());
+            self
+        }
+
+        /// Reports calls, retries and open streams to `metrics`. Token fetches are reported by
+        /// the `TokenCache`, which may be shared with other clients; see
+        /// `TokenCache::with_metrics`.
+        pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+            self.metrics = Some(metrics);
+            self
+        }
+
+        /// Propagates the trace returned by `current`, e.g. the caller's active span, on every
+        /// call.
+        pub fn with_trace_context<F>(mut self, current: F) -> Self
+        where
+            F: Fn() -> Option<TraceContext> + Send + Sync + 'static,
+        {
+            self.trace_context = Some(Arc::new(current));
+            self
+        }
+
+        /// Deadline for calls whose request does not carry one from `deadline::with_deadline`.
+        pub fn with_default_deadline(mut self, timeout: Duration) -> Self {
+            self.deadline = Some(timeout);
+            self
+        }
+
+        pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+            self.retry = retry;
+            self
         }
 
         pub fn new_document(&self, name: &str) -> Document {
             Document::new(&self.project_id, name)
         }
 
-        async fn add_metadata_to_request<X, R: tonic::IntoRequest<X>>(
-            &mut self,
+        fn add_metadata_to_request<X, R: tonic::IntoRequest<X>>(
+            &self,
             document: R,
-        ) -> Result<tonic::Request<X>, Box<dyn Error>> {
+            token: &str,
+        ) -> Result<tonic::Request<X>, Error> {
             let mut request = document.into_request();
-            let meta = request.metadata_mut();
-            let token = format!(
-                "Bearer {}",
-                self.credentials
-                    .get_oauth_token("https://www.googleapis.com/auth/datastore")
-                    .await?
-            );
-            meta.insert("authorization", MetadataValue::from_str(&token)?);
+            authorize(request.metadata_mut(), token)?;
             Ok(request)
         }
 
+        /// Adds the headers Google front ends route and attribute calls by: the database
+        /// `resource` belongs to, the client library and the project billed for quota.
+        fn add_routing_headers(
+            &self,
+            metadata: &mut MetadataMap,
+            resource: &str,
+        ) -> Result<(), Error> {
+            let database = database_of(resource)
+                .unwrap_or_else(|| format!("projects/{}/databases/(default)", &self.project_id));
+            let params = format!("database={}", percent_encode(&database));
+            let params = MetadataValue::from_str(&params)
+                .map_err(|_| Error::InvalidPath(resource.to_owned()))?;
+            metadata.insert("x-goog-request-params", params);
+            metadata.insert("x-goog-api-client", MetadataValue::from_static(API_CLIENT));
+            if let Some(project) = &self.quota_project {
+                let project = MetadataValue::from_str(project)
+                    .map_err(|e| Error::credentials("the quota project", e))?;
+                metadata.insert("x-goog-user-project", project);
+            }
+            Ok(())
+        }
+
+        fn add_trace_headers(&self, metadata: &mut MetadataMap) {
+            let current = self.trace_context.as_ref().and_then(|current| current());
+            if let Some(context) = current {
+                context.child().inject(metadata);
+            }
+        }
+
+        /// Fills in the status code and latency of a call's span and reports the call.
+        fn finish_call<T>(
+            &self,
+            method: &str,
+            span: &tracing::Span,
+            started: Instant,
+            result: &Result<T, Error>,
+        ) {
+            let code = match result {
+                Ok(_) => "Ok".to_owned(),
+                Err(error) => code_name(error),
+            };
+            let latency = started.elapsed();
+            span.record("code", &code.as_str());
+            span.record("latency_ms", &(latency.as_millis() as u64));
+            if let Some(metrics) = &self.metrics {
+                metrics.rpc_finished(method, &code, latency);
+            }
+        }
+
+        fn stream<T>(
+            &self,
+            method: &'static str,
+            inner: Streaming<T>,
+            lease: Lease,
+        ) -> ResponseStream<T> {
+            let gauge = self.metrics.clone().map(|metrics| {
+                metrics.stream_opened(method);
+                StreamGauge { method, metrics }
+            });
+            ResponseStream {
+                inner,
+                _lease: lease,
+                _gauge: gauge,
+            }
+        }
+
         pub fn generate_document_prefix(&self, name: &str) -> String {
             if name.len() > 0 {
                 format!(
@@ -68,80 +256,582 @@ pub mod v1 {
             }
         }
 
+        /// Makes the call, repeating it while the retry policy allows and the deadline, from
+        /// the request's `grpc-timeout` or the client default, has not passed. Returns the
+        /// connection lease along with the response so streams can hold on to it.
+        async fn call<X, R, F, Fut>(
+            &self,
+            method: &'static str,
+            idempotency: Idempotency,
+            request: impl tonic::IntoRequest<X>,
+            call: F,
+        ) -> Result<(tonic::Response<R>, Lease), Error>
+        where
+            X: Clone + Resource,
+            F: Fn(FirestoreClient<GrpcTimeout<PooledChannel>>, tonic::Request<X>) -> Fut,
+            Fut: Future<Output = Result<tonic::Response<R>, tonic::Status>>,
+        {
+            let request = request.into_request();
+            let span = tracing::info_span!(
+                "firestore.rpc",
+                method,
+                resource = request.get_ref().resource(),
+                code = tracing::field::Empty,
+                attempts = tracing::field::Empty,
+                latency_ms = tracing::field::Empty,
+            );
+            let started = Instant::now();
+            let mut attempts = 0;
+            let result = self
+                .call_with_retries(method, idempotency, request, call, &mut attempts)
+                .instrument(span.clone())
+                .await;
+
+            span.record("attempts", &attempts);
+            self.finish_call(method, &span, started, &result);
+            result
+        }
+
+        async fn call_with_retries<X, R, F, Fut>(
+            &self,
+            method: &'static str,
+            idempotency: Idempotency,
+            request: tonic::Request<X>,
+            call: F,
+            attempts: &mut u32,
+        ) -> Result<(tonic::Response<R>, Lease), Error>
+        where
+            X: Clone + Resource,
+            F: Fn(FirestoreClient<GrpcTimeout<PooledChannel>>, tonic::Request<X>) -> Fut,
+            Fut: Future<Output = Result<tonic::Response<R>, tonic::Status>>,
+        {
+            let timeout = deadline::timeout_of(request.metadata()).or(self.deadline);
+            let started = Instant::now();
+            let expires_at = timeout.map(|timeout| started + timeout);
+            let mut metadata = request.metadata().clone();
+            let message = request.into_inner();
+            self.add_routing_headers(&mut metadata, message.resource())?;
+
+            let mut attempt = 1;
+            loop {
+                *attempts = attempt;
+                let span = tracing::debug_span!("attempt", attempt);
+                let mut metadata = metadata.clone();
+                self.add_trace_headers(&mut metadata);
+                let result = match (timeout, expires_at) {
+                    (Some(timeout), Some(expires_at)) => {
+                        let remaining = expires_at.saturating_duration_since(Instant::now());
+                        let pending = self.call_with_auth_retry(
+                            message.clone(),
+                            &metadata,
+                            Some(remaining),
+                            &call,
+                        );
+                        tokio::time::timeout(remaining, pending.instrument(span))
+                            .await
+                            .unwrap_or_else(|_| Err(Error::deadline_exceeded(timeout)))
+                    }
+                    _ => {
+                        self.call_with_auth_retry(message.clone(), &metadata, None, &call)
+                            .instrument(span)
+                            .await
+                    }
+                };
+                let error = match result {
+                    Err(error) => error,
+                    response => return response,
+                };
+                let delay = self
+                    .retry
+                    .delay(idempotency, attempt, started.elapsed(), &error);
+                match delay {
+                    Some(delay) if expires_at.map_or(true, |at| Instant::now() + delay < at) => {
+                        tracing::debug!(attempt, ?delay, %error, "retrying");
+                        if let Some(metrics) = &self.metrics {
+                            metrics.rpc_retried(method, &code_name(&error));
+                        }
+                        tokio::time::delay_for(delay).await
+                    }
+                    _ => return Err(error),
+                }
+                attempt += 1;
+            }
+        }
+
+        /// Sends a request with a bearer token attached down the least loaded connection. When
+        /// the server rejects the token as stale, the cached token is dropped and the call is
+        /// made once more with a fresh one.
+        async fn call_with_auth_retry<X, R, F, Fut>(
+            &self,
+            message: X,
+            metadata: &MetadataMap,
+            timeout: Option<Duration>,
+            call: F,
+        ) -> Result<(tonic::Response<R>, Lease), Error>
+        where
+            X: Clone,
+            F: Fn(FirestoreClient<GrpcTimeout<PooledChannel>>, tonic::Request<X>) -> Fut,
+            Fut: Future<Output = Result<tonic::Response<R>, tonic::Status>>,
+        {
+            let request = |message| {
+                let mut request = tonic::Request::new(message);
+                *request.metadata_mut() = metadata.clone();
+                request
+            };
+            let lease = self.channels.acquire().await?;
+            let token = self.token.get(FIRESTORE_SCOPE).await?;
+            let req = self.add_metadata_to_request(request(message.clone()), &token)?;
+            let client = || FirestoreClient::new(GrpcTimeout::new(lease.channel(), timeout));
+            let response = match call(client(), req).await {
+                Err(status) if status.code() == Code::Unauthenticated => {
+                    self.token.invalidate(FIRESTORE_SCOPE, &token).await;
+                    let token = self.token.get(FIRESTORE_SCOPE).await?;
+                    let req = self.add_metadata_to_request(request(message), &token)?;
+                    call(client(), req).await
+                }
+                response => response,
+            };
+            match response {
+                Ok(response) => Ok((response, lease)),
+                Err(status) => Err(status.into()),
+            }
+        }
+
         pub async fn create_document(
-            &mut self,
-            request: CreateDocumentRequest,
-        ) -> Result<tonic::Response<Document>, tonic::Status> {
-            let req = self.add_metadata_to_request(request).await.map_err(|_| {
-                tonic::Status::new(
-                    Code::FailedPrecondition,
-                    "Unable to add metadata to  create document request",
-                )
-            })?;
-            self.service
-                .create_document(req)
-                .await
-                .map(transform_response_to_document_response(&self.project_id))
+            &self,
+            request: impl tonic::IntoRequest<CreateDocumentRequest>,
+        ) -> Result<tonic::Response<Document>, Error> {
+            self.call(
+                "CreateDocument",
+                Idempotency::NonIdempotent,
+                request,
+                |mut service, req| async move { service.create_document(req).await },
+            )
+            .await
+            .and_then(|(response, _)| {
+                transform_response_to_document_response(&self.project_id)(response)
+            })
         }
 
         pub async fn get_document(
-            &mut self,
-            request: GetDocumentRequest,
-        ) -> Result<tonic::Response<Document>, tonic::Status> {
-            let req = self.add_metadata_to_request(request).await.map_err(|_| {
-                tonic::Status::new(
-                    Code::FailedPrecondition,
-                    "Unable to add metadata to get document request",
-                )
-            })?;
-            self.service
-                .get_document(req)
-                .await
-                .map(transform_response_to_document_response(&self.project_id))
+            &self,
+            request: impl tonic::IntoRequest<GetDocumentRequest>,
+        ) -> Result<tonic::Response<Document>, Error> {
+            self.call(
+                "GetDocument",
+                Idempotency::Idempotent,
+                request,
+                |mut service, req| async move { service.get_document(req).await },
+            )
+            .await
+            .and_then(|(response, _)| {
+                transform_response_to_document_response(&self.project_id)(response)
+            })
         }
 
         pub async fn update_document(
-            &mut self,
-            request: UpdateDocumentRequest,
-        ) -> Result<tonic::Response<Document>, tonic::Status> {
-            let req = self.add_metadata_to_request(request).await.map_err(|_| {
-                tonic::Status::new(
-                    Code::FailedPrecondition,
-                    "Unable to add metadata to update document request",
-                )
-            })?;
-
-            self.service
-                .update_document(req)
-                .await
-                .map(transform_response_to_document_response(&self.project_id))
+            &self,
+            request: impl tonic::IntoRequest<UpdateDocumentRequest>,
+        ) -> Result<tonic::Response<Document>, Error> {
+            self.call(
+                "UpdateDocument",
+                Idempotency::NonIdempotent,
+                request,
+                |mut service, req| async move { service.update_document(req).await },
+            )
+            .await
+            .and_then(|(response, _)| {
+                transform_response_to_document_response(&self.project_id)(response)
+            })
         }
 
         pub async fn delete_document(
-            &mut self,
-            request: DeleteDocumentRequest,
-        ) -> Result<tonic::Response<()>, tonic::Status> {
-            let req = self.add_metadata_to_request(request).await.map_err(|_| {
-                tonic::Status::new(
-                    Code::FailedPrecondition,
-                    "Unable to add metadata to  delete document request",
+            &self,
+            request: impl tonic::IntoRequest<DeleteDocumentRequest>,
+        ) -> Result<tonic::Response<()>, Error> {
+            self.call(
+                "DeleteDocument",
+                Idempotency::Idempotent,
+                request,
+                |mut service, req| async move { service.delete_document(req).await },
+            )
+            .await
+            .map(|(response, _)| response)
+        }
+
+        /// Streams the results of a query. Dropping the stream cancels the query.
+        pub async fn run_query(
+            &self,
+            request: impl tonic::IntoRequest<RunQueryRequest>,
+        ) -> Result<ResponseStream<RunQueryResponse>, Error> {
+            let (response, lease) = self
+                .call(
+                    "RunQuery",
+                    Idempotency::Idempotent,
+                    request,
+                    |mut service, req| async move { service.run_query(req).await },
+                )
+                .await?;
+            Ok(self.stream("RunQuery", response.into_inner(), lease))
+        }
+
+        /// Streams documents as they are read. Dropping the stream cancels the read.
+        pub async fn batch_get_documents(
+            &self,
+            request: impl tonic::IntoRequest<BatchGetDocumentsRequest>,
+        ) -> Result<ResponseStream<BatchGetDocumentsResponse>, Error> {
+            let (response, lease) = self
+                .call(
+                    "BatchGetDocuments",
+                    Idempotency::Idempotent,
+                    request,
+                    |mut service, req| async move { service.batch_get_documents(req).await },
                 )
-            })?;
-            self.service.delete_document(req).await
+                .await?;
+            Ok(self.stream("BatchGetDocuments", response.into_inner(), lease))
+        }
+
+        /// Opens a listen stream fed by `requests`. Listen streams are long-lived, so only a
+        /// deadline set on the request itself applies, and the stream is opened just once.
+        /// The call is routed by the database the first request names, so it is only made once
+        /// that request is available. Dropping the stream ends it.
+        pub async fn listen(
+            &self,
+            requests: impl tonic::IntoStreamingRequest<Message = ListenRequest>,
+        ) -> Result<ResponseStream<ListenResponse>, Error> {
+            let request = requests.into_streaming_request();
+            let metadata = request.metadata().clone();
+            let mut messages = Box::pin(request.into_inner());
+            let first = messages.next().await;
+            let resource = first
+                .as_ref()
+                .map_or("", |first| first.resource())
+                .to_owned();
+            let mut request = tonic::Request::new(tokio::stream::iter(first).chain(messages));
+            *request.metadata_mut() = metadata;
+
+            let span = tracing::info_span!(
+                "firestore.rpc",
+                method = "Listen",
+                resource = resource.as_str(),
+                code = tracing::field::Empty,
+                attempts = 1,
+                latency_ms = tracing::field::Empty,
+            );
+            let started = Instant::now();
+            let result = self
+                .open_listen(request, &resource)
+                .instrument(span.clone())
+                .await;
+            self.finish_call("Listen", &span, started, &result);
+            let (response, lease) = result?;
+            Ok(self.stream("Listen", response.into_inner(), lease))
+        }
+
+        async fn open_listen<S>(
+            &self,
+            mut request: tonic::Request<S>,
+            resource: &str,
+        ) -> Result<(tonic::Response<Streaming<ListenResponse>>, Lease), Error>
+        where
+            S: Stream<Item = ListenRequest> + Send + Sync + 'static,
+        {
+            let lease = self.channels.acquire().await?;
+            let token = self.token.get(FIRESTORE_SCOPE).await?;
+            authorize(request.metadata_mut(), &token)?;
+            self.add_routing_headers(request.metadata_mut(), resource)?;
+            self.add_trace_headers(request.metadata_mut());
+            let timeout = deadline::timeout_of(request.metadata());
+            let mut service = FirestoreClient::new(GrpcTimeout::new(lease.channel(), timeout));
+            let response = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, service.listen(request))
+                    .await
+                    .map_err(|_| Error::deadline_exceeded(timeout))??,
+                None => service.listen(request).await?,
+            };
+            Ok((response, lease))
+        }
+    }
+
+    /// Messages from a streaming call. Holds its connection's stream slot in the pool until
+    /// dropped, which also cancels the call.
+    pub struct ResponseStream<T> {
+        inner: Streaming<T>,
+        _lease: Lease,
+        _gauge: Option<StreamGauge>,
+    }
+
+    // Counts the stream as open in the metrics for as long as it lives.
+    struct StreamGauge {
+        method: &'static str,
+        metrics: Arc<dyn Metrics>,
+    }
+
+    impl Drop for StreamGauge {
+        fn drop(&mut self) {
+            self.metrics.stream_closed(self.method);
+        }
+    }
+
+    impl<T> Stream for ResponseStream<T> {
+        type Item = Result<T, Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Pin::new(&mut self.inner)
+                .poll_next(cx)
+                .map(|message| message.map(|message| message.map_err(Error::from)))
+        }
+    }
+
+    /// Configures a `Firestore` connection. Unset options fall back to the environment: the
+    /// emulator named by `FIRESTORE_EMULATOR_HOST`, Application Default Credentials and
+    /// `GOOGLE_CLOUD_PROJECT`.
+    #[derive(Default)]
+    pub struct FirestoreBuilder {
+        endpoint: Option<GrpcEndpoint>,
+        project_id: Option<String>,
+        token: Option<TokenCache>,
+        token_source: Option<Box<dyn TokenSource>>,
+        emulator_host: Option<String>,
+        pool: PoolOptions,
+        retry: RetryPolicy,
+        deadline: Option<Duration>,
+        quota_project: Option<String>,
+        trace_context: Option<Arc<dyn Fn() -> Option<TraceContext> + Send + Sync>>,
+        metrics: Option<Arc<dyn Metrics>>,
+    }
+
+    impl FirestoreBuilder {
+        pub fn endpoint(mut self, endpoint: GrpcEndpoint) -> Self {
+            self.endpoint = Some(endpoint);
+            self
+        }
+
+        pub fn project_id<P: Into<String>>(mut self, project_id: P) -> Self {
+            self.project_id = Some(project_id.into());
+            self
+        }
+
+        pub fn token_source<T: TokenSource + 'static>(mut self, token_source: T) -> Self {
+            self.token_source = Some(Box::new(token_source));
+            self.token = None;
+            self
+        }
+
+        /// Shares `token` with other clients. Its token fetches are reported to whatever metrics
+        /// it was built with, not to the ones given to this builder.
+        pub fn token_cache(mut self, token: TokenCache) -> Self {
+            self.token = Some(token);
+            self.token_source = None;
+            self
+        }
+
+        /// How many connections to open and balance calls across.
+        pub fn pool(mut self, options: PoolOptions) -> Self {
+            self.pool = options;
+            self
+        }
+
+        pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+            self.retry = retry;
+            self
+        }
+
+        pub fn default_deadline(mut self, timeout: Duration) -> Self {
+            self.deadline = Some(timeout);
+            self
+        }
+
+        pub fn quota_project<P: Into<String>>(mut self, project: P) -> Self {
+            self.quota_project = Some(project.into());
+            self
+        }
+
+        /// Reports calls to `metrics`, along with the token fetches of the cache this builder
+        /// makes, unless a shared `token_cache` is given.
+        pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+            self.metrics = Some(metrics);
+            self
+        }
+
+        pub fn trace_context<F>(mut self, current: F) -> Self
+        where
+            F: Fn() -> Option<TraceContext> + Send + Sync + 'static,
+        {
+            self.trace_context = Some(Arc::new(current));
+            self
+        }
+
+        /// Talks plaintext HTTP/2 to the emulator at `host:port` with the emulator's
+        /// `Bearer owner` token, ignoring any configured credentials.
+        pub fn emulator<S: Into<String>>(mut self, host: S) -> Self {
+            self.emulator_host = Some(host.into());
+            self
+        }
+
+        pub async fn connect(self) -> Result<Firestore, Error> {
+            let metrics = self.metrics.clone();
+            let cache = |source: Box<dyn TokenSource>| match &metrics {
+                Some(metrics) => TokenCache::with_metrics(source, metrics.clone()),
+                None => TokenCache::new(source),
+            };
+            let token = match (self.token, self.token_source) {
+                (Some(token), _) => Some(token),
+                (None, Some(source)) => Some(cache(source)),
+                (None, None) => None,
+            };
+
+            let emulator_host = match (&self.emulator_host, &self.endpoint) {
+                (Some(host), _) => Some(host.clone()),
+                (None, None) => std::env::var("FIRESTORE_EMULATOR_HOST").ok(),
+                (None, Some(_)) => None,
+            };
+
+            let (endpoint, token, project_id) = if let Some(host) = emulator_host {
+                let project_id = match self.project_id {
+                    Some(project_id) => project_id,
+                    None => project_id_from_env()?,
+                };
+                let token = cache(Box::new(StaticToken::new("owner")));
+                (GrpcEndpoint::insecure(&host), token, project_id)
+            } else {
+                let endpoint = self
+                    .endpoint
+                    .unwrap_or_else(|| GrpcEndpoint::new("firestore.googleapis.com"));
+                match (token, self.project_id) {
+                    (Some(token), Some(project_id)) => (endpoint, token, project_id),
+                    (Some(token), None) => (endpoint, token, project_id_from_env()?),
+                    (None, project_id) => {
+                        let credentials = ApplicationCredentials::find().await?;
+                        let project_id = match project_id {
+                            Some(project_id) => project_id,
+                            None => credentials.project_id().await?,
+                        };
+                        (endpoint, cache(Box::new(credentials)), project_id)
+                    }
+                }
+            };
+
+            let channels = ChannelPool::connect(endpoint, self.pool).await?;
+            let mut firestore = Firestore::connect_with_pool(channels, project_id, token)
+                .with_retry_policy(self.retry);
+            firestore.deadline = self.deadline;
+            firestore.trace_context = self.trace_context;
+            if let Some(metrics) = self.metrics {
+                firestore = firestore.with_metrics(metrics);
+            }
+            if let Some(project) = self.quota_project {
+                firestore.quota_project = Some(project);
+            }
+            Ok(firestore)
+        }
+    }
+
+    /// The resource a request acts on, which decides where it is routed.
+    trait Resource {
+        fn resource(&self) -> &str;
+    }
+
+    impl Resource for CreateDocumentRequest {
+        fn resource(&self) -> &str {
+            &self.parent
+        }
+    }
+
+    impl Resource for GetDocumentRequest {
+        fn resource(&self) -> &str {
+            &self.name
+        }
+    }
+
+    impl Resource for UpdateDocumentRequest {
+        fn resource(&self) -> &str {
+            self.document.as_ref().map_or("", |document| &document.name)
+        }
+    }
+
+    impl Resource for DeleteDocumentRequest {
+        fn resource(&self) -> &str {
+            &self.name
+        }
+    }
+
+    impl Resource for RunQueryRequest {
+        fn resource(&self) -> &str {
+            &self.parent
+        }
+    }
+
+    impl Resource for BatchGetDocumentsRequest {
+        fn resource(&self) -> &str {
+            &self.database
+        }
+    }
+
+    impl Resource for ListenRequest {
+        fn resource(&self) -> &str {
+            &self.database
+        }
+    }
+
+    /// `projects/{project}/databases/{database}` out of any resource name below it.
+    fn database_of(resource: &str) -> Option<String> {
+        let segments: Vec<&str> = resource.splitn(5, '/').take(4).collect();
+        match segments.as_slice() {
+            ["projects", project, "databases", database]
+                if !project.is_empty() && !database.is_empty() =>
+            {
+                Some(segments.join("/"))
+            }
+            _ => None,
         }
     }
 
+    fn authorize(metadata: &mut MetadataMap, token: &str) -> Result<(), Error> {
+        let token = format!("Bearer {}", token);
+        let value = MetadataValue::from_str(&token).map_err(Error::token)?;
+        metadata.insert("authorization", value);
+        Ok(())
+    }
+
+    fn percent_encode(value: &str) -> String {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (byte as char).to_string()
+                }
+                _ => format!("%{:02X}", byte),
+            })
+            .collect()
+    }
+
+    fn code_name(error: &Error) -> String {
+        error
+            .code()
+            .map_or_else(|| "Transport".to_owned(), |code| format!("{:?}", code))
+    }
+
+    fn project_id_from_env() -> Result<String, Error> {
+        connection::project_id_from_env().ok_or_else(|| {
+            Error::credentials(
+                "the environment",
+                "No project id given and neither GOOGLE_CLOUD_PROJECT nor GCLOUD_PROJECT is set",
+            )
+        })
+    }
+
     fn transform_response_to_document_response<S: AsRef<str>>(
         project_id: S,
-    ) -> impl Fn(Response<RPCDocument>) -> Response<Document> {
+    ) -> impl Fn(Response<RPCDocument>) -> Result<Response<Document>, Error> {
         move |response| {
             let mut resp = Response::new(Document::from_rpc_document(
                 response.get_ref(),
                 project_id.as_ref(),
-            ));
+            )?);
             let metadata = resp.metadata_mut();
             *metadata = response.metadata().to_owned();
-            resp
+            Ok(resp)
         }
     }
 
@@ -173,38 +863,292 @@ pub mod v1 {
         fn into_value(self) -> Value;
     }
 
+    /// Conversions that can fail, such as unsigned integers too large for Firestore's signed
+    /// 64-bit integers. Every `IntoDocumentValue` type in this module converts infallibly, and
+    /// containers of fallible values fail on their first bad element.
+    pub trait TryIntoDocumentValue {
+        fn try_into_value(self) -> Result<Value, Error>;
+    }
+
+    macro_rules! infallible_values {
+        ($($value:ty),*) => {
+            $(
+                impl TryIntoDocumentValue for $value {
+                    fn try_into_value(self) -> Result<Value, Error> {
+                        Ok(self.into_value())
+                    }
+                }
+            )*
+        };
+    }
+
+    infallible_values!(
+        Value,
+        String,
+        bool,
+        i8,
+        i16,
+        i32,
+        i64,
+        u8,
+        u16,
+        u32,
+        f32,
+        f64,
+        Bytes,
+        Timestamp,
+        SystemTime,
+        LatLng,
+        DocumentReference
+    );
+
+    impl<'a> TryIntoDocumentValue for &'a str {
+        fn try_into_value(self) -> Result<Value, Error> {
+            Ok(self.into_value())
+        }
+    }
+
+    fn value_of(value_type: ValueType) -> Value {
+        Value {
+            value_type: Some(value_type),
+        }
+    }
+
+    impl IntoDocumentValue for Value {
+        fn into_value(self) -> Value {
+            self
+        }
+    }
+
     impl IntoDocumentValue for String {
         fn into_value(self) -> Value {
-            Value {
-                value_type: Some(ValueType::StringValue(self)),
-            }
+            value_of(ValueType::StringValue(self))
         }
     }
 
-    impl IntoDocumentValue for &'static str {
+    impl<'a> IntoDocumentValue for &'a str {
         fn into_value(self) -> Value {
-            Value {
-                value_type: Some(ValueType::StringValue(self.to_owned())),
-            }
+            value_of(ValueType::StringValue(self.to_owned()))
         }
     }
 
-    impl IntoDocumentValue for i64 {
+    impl IntoDocumentValue for bool {
         fn into_value(self) -> Value {
-            Value {
-                value_type: Some(ValueType::IntegerValue(self)),
-            }
+            value_of(ValueType::BooleanValue(self))
+        }
+    }
+
+    macro_rules! integer_values {
+        ($($int:ty),*) => {
+            $(
+                impl IntoDocumentValue for $int {
+                    fn into_value(self) -> Value {
+                        value_of(ValueType::IntegerValue(i64::from(self)))
+                    }
+                }
+            )*
+        };
+    }
+
+    integer_values!(i8, i16, i32, i64, u8, u16, u32);
+
+    macro_rules! checked_integer_values {
+        ($($int:ty),*) => {
+            $(
+                impl TryIntoDocumentValue for $int {
+                    fn try_into_value(self) -> Result<Value, Error> {
+                        i64::try_from(self)
+                            .map(|int| value_of(ValueType::IntegerValue(int)))
+                            .map_err(|_| {
+                                Error::InvalidValue(format!(
+                                    "{} does not fit in a 64-bit signed integer",
+                                    self
+                                ))
+                            })
+                    }
+                }
+            )*
+        };
+    }
+
+    checked_integer_values!(isize, u64, usize);
+
+    impl IntoDocumentValue for f32 {
+        fn into_value(self) -> Value {
+            value_of(ValueType::DoubleValue(f64::from(self)))
         }
     }
 
     impl IntoDocumentValue for f64 {
         fn into_value(self) -> Value {
-            Value {
-                value_type: Some(ValueType::DoubleValue(self)),
+            value_of(ValueType::DoubleValue(self))
+        }
+    }
+
+    /// `None` is stored as null.
+    impl<T: IntoDocumentValue> IntoDocumentValue for Option<T> {
+        fn into_value(self) -> Value {
+            match self {
+                Some(value) => value.into_value(),
+                None => value_of(ValueType::NullValue(
+                    prost_types::NullValue::NullValue as i32,
+                )),
+            }
+        }
+    }
+
+    impl<T: IntoDocumentValue> IntoDocumentValue for Vec<T> {
+        fn into_value(self) -> Value {
+            value_of(ValueType::ArrayValue(ArrayValue {
+                values: self
+                    .into_iter()
+                    .map(IntoDocumentValue::into_value)
+                    .collect(),
+            }))
+        }
+    }
+
+    impl<T: IntoDocumentValue> IntoDocumentValue for HashMap<String, T> {
+        fn into_value(self) -> Value {
+            map_value(self)
+        }
+    }
+
+    impl<T: IntoDocumentValue> IntoDocumentValue for BTreeMap<String, T> {
+        fn into_value(self) -> Value {
+            map_value(self)
+        }
+    }
+
+    impl<T: TryIntoDocumentValue> TryIntoDocumentValue for Option<T> {
+        fn try_into_value(self) -> Result<Value, Error> {
+            match self {
+                Some(value) => value.try_into_value(),
+                None => Ok(None::<Value>.into_value()),
             }
         }
     }
 
+    impl<T: TryIntoDocumentValue> TryIntoDocumentValue for Vec<T> {
+        fn try_into_value(self) -> Result<Value, Error> {
+            Ok(value_of(ValueType::ArrayValue(ArrayValue {
+                values: self
+                    .into_iter()
+                    .map(TryIntoDocumentValue::try_into_value)
+                    .collect::<Result<_, _>>()?,
+            })))
+        }
+    }
+
+    impl<T: TryIntoDocumentValue> TryIntoDocumentValue for HashMap<String, T> {
+        fn try_into_value(self) -> Result<Value, Error> {
+            try_map_value(self)
+        }
+    }
+
+    impl<T: TryIntoDocumentValue> TryIntoDocumentValue for BTreeMap<String, T> {
+        fn try_into_value(self) -> Result<Value, Error> {
+            try_map_value(self)
+        }
+    }
+
+    fn try_map_value<T: TryIntoDocumentValue, I: IntoIterator<Item = (String, T)>>(
+        fields: I,
+    ) -> Result<Value, Error> {
+        Ok(value_of(ValueType::MapValue(MapValue {
+            fields: fields
+                .into_iter()
+                .map(|(key, value)| Ok((key, value.try_into_value()?)))
+                .collect::<Result<_, Error>>()?,
+        })))
+    }
+
+    fn map_value<T: IntoDocumentValue, I: IntoIterator<Item = (String, T)>>(fields: I) -> Value {
+        value_of(ValueType::MapValue(MapValue {
+            fields: fields
+                .into_iter()
+                .map(|(key, value)| (key, value.into_value()))
+                .collect(),
+        }))
+    }
+
+    /// A byte buffer, stored as a bytes value. A plain `Vec<u8>` is stored as an array of
+    /// integers instead.
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct Bytes(pub Vec<u8>);
+
+    impl From<Vec<u8>> for Bytes {
+        fn from(bytes: Vec<u8>) -> Self {
+            Bytes(bytes)
+        }
+    }
+
+    impl<'a> From<&'a [u8]> for Bytes {
+        fn from(bytes: &'a [u8]) -> Self {
+            Bytes(bytes.to_vec())
+        }
+    }
+
+    impl IntoDocumentValue for Bytes {
+        fn into_value(self) -> Value {
+            value_of(ValueType::BytesValue(self.0))
+        }
+    }
+
+    impl IntoDocumentValue for Timestamp {
+        fn into_value(self) -> Value {
+            value_of(ValueType::TimestampValue(self))
+        }
+    }
+
+    impl IntoDocumentValue for SystemTime {
+        fn into_value(self) -> Value {
+            Timestamp::from(self).into_value()
+        }
+    }
+
+    impl IntoDocumentValue for LatLng {
+        fn into_value(self) -> Value {
+            value_of(ValueType::GeoPointValue(self))
+        }
+    }
+
+    /// A reference to another document, stored by its full resource name.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct DocumentReference(String);
+
+    impl DocumentReference {
+        /// `path` is relative to the default database, e.g. `users/alice`.
+        pub fn new<P: AsRef<str>>(project_id: &str, path: P) -> Self {
+            DocumentReference(format!(
+                "{}/{}",
+                create_firestore_default_prefix(project_id),
+                path.as_ref().trim_matches('/')
+            ))
+        }
+
+        /// Takes a full resource name, e.g. one read back from a reference value.
+        pub fn from_name<S: Into<String>>(name: S) -> Self {
+            DocumentReference(name.into())
+        }
+
+        pub fn name(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl IntoDocumentValue for DocumentReference {
+        fn into_value(self) -> Value {
+            value_of(ValueType::ReferenceValue(self.0))
+        }
+    }
+
+    impl<'a> From<&'a Document> for DocumentReference {
+        fn from(document: &'a Document) -> Self {
+            DocumentReference(document.as_rpc_document().name)
+        }
+    }
+
     impl Document {
         pub fn new<Name: AsRef<str>>(project_id: &str, name: Name) -> Self {
             Document {
@@ -221,19 +1165,22 @@ pub mod v1 {
             }
         }
 
-        pub fn from_rpc_document(d: &RPCDocument, project_id: &str) -> Self {
+        pub fn from_rpc_document(d: &RPCDocument, project_id: &str) -> Result<Self, Error> {
             let d = d.to_owned();
             let address: Vec<String> = d.name.split("/").map(|i| i.to_owned()).collect();
             // all returned documents from the server have full paths, we want to strip that and only take the required path;
             // TOOD: Make this better somehow
+            if address.len() < 7 {
+                return Err(Error::InvalidPath(d.name));
+            }
             let address = address[5..].to_vec();
-            Document {
+            Ok(Document {
                 inner: d.clone(),
                 fields: d.fields,
-                name: address.last().expect("Document has no name").to_owned(),
+                name: address.last().unwrap().to_owned(),
                 address: address[0..address.len() - 1].to_vec(),
                 project_id: project_id.to_owned(),
-            }
+            })
         }
 
         pub fn as_rpc_document(&self) -> RPCDocument {
@@ -263,6 +1210,18 @@ pub mod v1 {
             }
         }
 
+        /// Like `set_field`, for values whose conversion can fail, e.g. a `u64` above
+        /// `i64::MAX`. The document is left unchanged on error.
+        pub fn try_set_field<F: AsRef<str>, T: TryIntoDocumentValue>(
+            &mut self,
+            field_name: F,
+            field_value: T,
+        ) -> Result<(), Error> {
+            let value = field_value.try_into_value()?;
+            self.set_field(field_name, value);
+            Ok(())
+        }
+
         pub fn get_field_value<F: AsRef<str>>(&self, key: F) -> Option<ValueType> {
             let maybe = self.fields.get(key.as_ref()).map(|v| v.value_type.clone());
             match maybe {
@@ -338,4 +1297,158 @@ pub mod v1 {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn it_routes_by_database() {
+            assert_eq!(
+                database_of("projects/p/databases/(default)/documents/users/alice").as_deref(),
+                Some("projects/p/databases/(default)")
+            );
+            assert_eq!(database_of("projects/p"), None);
+            assert_eq!(
+                percent_encode("projects/p/databases/(default)"),
+                "projects%2Fp%2Fdatabases%2F%28default%29"
+            );
+        }
+
+        #[test]
+        fn it_reports_the_rust_and_crate_versions() {
+            let tokens: Vec<&str> = API_CLIENT.split(' ').collect();
+            assert!(tokens[0].starts_with("gl-rust/1."));
+            assert_eq!(
+                tokens[1],
+                format!("rust_googleapis_grpc/{}", env!("CARGO_PKG_VERSION"))
+            );
+        }
+
+        #[test]
+        fn it_is_shared_between_tasks() {
+            fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+            fn assert_send<T: Send>(_: T) {}
+            assert_shareable::<Firestore>();
+            let _ = |firestore: &Firestore| {
+                assert_send(firestore.get_document(GetDocumentRequest::default()));
+                assert_send(firestore.listen(tokio::stream::iter(vec![ListenRequest::default()])));
+            };
+        }
+
+        #[test]
+        fn it_connects_with_any_kind_of_credentials() {
+            use crate::connection::{
+                AuthorizedUser, Credentials, ExternalAccount, ImpersonatedServiceAccount,
+                MetadataServer,
+            };
+
+            let _ = || Firestore::connect(Credentials::from_json("").unwrap());
+            let _ = || async { Firestore::connect(Credentials::auto_acquire().await?).await };
+            let _ = || Firestore::connect(AuthorizedUser::from_json("").unwrap());
+            let _ = || Firestore::connect(ExternalAccount::from_json("").unwrap());
+            let _ = || Firestore::connect(MetadataServer::new());
+            let _ = || {
+                let source = MetadataServer::new();
+                Firestore::connect(ImpersonatedServiceAccount::new(source, "target"))
+            };
+        }
+
+        #[test]
+        fn it_converts_values() {
+            let value_type = |value: Value| value.value_type.unwrap();
+            assert_eq!(value_type(true.into_value()), ValueType::BooleanValue(true));
+            assert_eq!(value_type(7u8.into_value()), ValueType::IntegerValue(7));
+            assert_eq!(
+                value_type((-7i32).into_value()),
+                ValueType::IntegerValue(-7)
+            );
+            let name = String::from("alice");
+            assert_eq!(
+                value_type(name.as_str().into_value()),
+                ValueType::StringValue("alice".to_owned())
+            );
+            assert_eq!(
+                value_type(None::<i64>.into_value()),
+                ValueType::NullValue(0)
+            );
+            assert_eq!(
+                value_type(vec![1i64, 2].into_value()),
+                ValueType::ArrayValue(ArrayValue {
+                    values: vec![1i64.into_value(), 2i64.into_value()],
+                })
+            );
+            let mut map = BTreeMap::new();
+            map.insert("age".to_owned(), 30u16);
+            match value_type(map.into_value()) {
+                ValueType::MapValue(map) => assert_eq!(map.fields["age"], 30i64.into_value()),
+                other => panic!("expected a map, got {:?}", other),
+            }
+            assert_eq!(
+                value_type(Bytes::from(&b"hi"[..]).into_value()),
+                ValueType::BytesValue(b"hi".to_vec())
+            );
+            assert_eq!(
+                value_type(SystemTime::UNIX_EPOCH.into_value()),
+                ValueType::TimestampValue(Timestamp {
+                    seconds: 0,
+                    nanos: 0
+                })
+            );
+            assert_eq!(
+                value_type(DocumentReference::new("p", "users/alice").into_value()),
+                ValueType::ReferenceValue(
+                    "projects/p/databases/(default)/documents/users/alice".to_owned()
+                )
+            );
+        }
+
+        #[test]
+        fn it_rejects_integers_out_of_range() {
+            assert_eq!(
+                value_of_u64(i64::MAX as u64),
+                Some(ValueType::IntegerValue(i64::MAX))
+            );
+            assert_eq!(value_of_u64(u64::MAX), None);
+
+            let mut document = Document::new("p", "alice");
+            assert!(document.try_set_field("visits", u64::MAX).is_err());
+            assert_eq!(document.get_field_value("visits"), None);
+            document.try_set_field("visits", 3u64).unwrap();
+            assert_eq!(
+                document.get_field_value("visits"),
+                Some(ValueType::IntegerValue(3))
+            );
+        }
+
+        #[test]
+        fn it_rejects_integers_out_of_range_inside_containers() {
+            let value_type = |value: Value| value.value_type.unwrap();
+            assert!(u64::MAX.try_into_value().is_err());
+            assert!(isize::MIN.try_into_value().is_ok());
+            assert!(Some(u64::MAX).try_into_value().is_err());
+            assert!(vec![1u64, u64::MAX].try_into_value().is_err());
+            let mut map = HashMap::new();
+            map.insert("visits".to_owned(), u64::MAX);
+            assert!(map.try_into_value().is_err());
+
+            assert_eq!(
+                value_type(None::<u64>.try_into_value().unwrap()),
+                ValueType::NullValue(prost_types::NullValue::NullValue as i32)
+            );
+            assert_eq!(
+                value_type(vec![Some(1usize), None].try_into_value().unwrap()),
+                value_type(vec![Some(1i64), None].into_value())
+            );
+            let mut map = BTreeMap::new();
+            map.insert("visits".to_owned(), vec![3u64]);
+            let mut expected = BTreeMap::new();
+            expected.insert("visits".to_owned(), vec![3i64]);
+            assert_eq!(map.try_into_value().unwrap(), expected.into_value());
+        }
+
+        fn value_of_u64(int: u64) -> Option<ValueType> {
+            int.try_into_value().ok()?.value_type
+        }
+    }
 }
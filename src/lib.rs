@@ -1,4 +1,5 @@
-mod google {
+/// Messages and raw clients generated from the Google API protos.
+pub mod google {
     pub mod firestore {
         pub mod v1 {
             include!("protodefs/google.firestore.v1.rs");
@@ -12,8 +13,15 @@ mod google {
     }
 }
 
-mod connection;
+pub mod connection;
+pub mod deadline;
+mod error;
+
+pub use error::{BoxError, Error, ErrorDetail};
 
 pub mod firestore;
+pub mod metrics;
+pub mod retry;
+pub mod trace;
 
 mod tests;
@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Receives measurements from clients and token caches. Every method defaults to doing
+/// nothing, so implementations only pick the ones they export.
+pub trait Metrics: Send + Sync {
+    /// A call finished, after all of its attempts. `code` is the gRPC status code name, `Ok`
+    /// on success.
+    fn rpc_finished(&self, _method: &str, _code: &str, _latency: Duration) {}
+
+    /// An attempt failed with `code` and the call is being made again.
+    fn rpc_retried(&self, _method: &str, _code: &str) {}
+
+    /// A token source was asked for a new token.
+    fn token_fetched(&self, _ok: bool, _latency: Duration) {}
+
+    fn stream_opened(&self, _method: &str) {}
+
+    fn stream_closed(&self, _method: &str) {}
+}
+
+// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Keeps measurements in memory and renders them in the Prometheus text exposition format,
+/// e.g. to serve from a `/metrics` endpoint.
+#[derive(Default)]
+pub struct InMemoryMetrics {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    rpcs: BTreeMap<(String, String), u64>,
+    rpc_latency: BTreeMap<String, Histogram>,
+    retries: BTreeMap<(String, String), u64>,
+    token_fetches: BTreeMap<&'static str, u64>,
+    token_latency: Histogram,
+    streams: BTreeMap<String, i64>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (count, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+impl InMemoryMetrics {
+    pub fn new() -> Self {
+        InMemoryMetrics::default()
+    }
+
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "firestore_rpc_total",
+            "counter",
+            "Completed Firestore calls.",
+        );
+        for ((method, code), count) in &state.rpcs {
+            let _ = writeln!(
+                out,
+                "firestore_rpc_total{{method=\"{}\",code=\"{}\"}} {}",
+                escape(method),
+                escape(code),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "firestore_rpc_latency_seconds",
+            "histogram",
+            "Firestore call latency, across all attempts.",
+        );
+        for (method, histogram) in &state.rpc_latency {
+            let labels = format!("method=\"{}\"", escape(method));
+            histogram.render(&mut out, "firestore_rpc_latency_seconds", &labels);
+        }
+
+        header(
+            &mut out,
+            "firestore_rpc_retries_total",
+            "counter",
+            "Firestore call attempts that failed and were retried.",
+        );
+        for ((method, code), count) in &state.retries {
+            let _ = writeln!(
+                out,
+                "firestore_rpc_retries_total{{method=\"{}\",code=\"{}\"}} {}",
+                escape(method),
+                escape(code),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "firestore_streams_in_flight",
+            "gauge",
+            "Open Firestore streams.",
+        );
+        for (method, open) in &state.streams {
+            let _ = writeln!(
+                out,
+                "firestore_streams_in_flight{{method=\"{}\"}} {}",
+                escape(method),
+                open
+            );
+        }
+
+        header(
+            &mut out,
+            "google_token_fetches_total",
+            "counter",
+            "Access tokens fetched from token sources.",
+        );
+        for (outcome, count) in &state.token_fetches {
+            let _ = writeln!(
+                out,
+                "google_token_fetches_total{{outcome=\"{}\"}} {}",
+                outcome, count
+            );
+        }
+
+        header(
+            &mut out,
+            "google_token_fetch_latency_seconds",
+            "histogram",
+            "Time taken to fetch access tokens.",
+        );
+        state
+            .token_latency
+            .render(&mut out, "google_token_fetch_latency_seconds", "");
+        out
+    }
+}
+
+impl Metrics for InMemoryMetrics {
+    fn rpc_finished(&self, method: &str, code: &str, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        *state
+            .rpcs
+            .entry((method.to_owned(), code.to_owned()))
+            .or_default() += 1;
+        state
+            .rpc_latency
+            .entry(method.to_owned())
+            .or_default()
+            .observe(latency);
+    }
+
+    fn rpc_retried(&self, method: &str, code: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state
+            .retries
+            .entry((method.to_owned(), code.to_owned()))
+            .or_default() += 1;
+    }
+
+    fn token_fetched(&self, ok: bool, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let outcome = if ok { "ok" } else { "error" };
+        *state.token_fetches.entry(outcome).or_default() += 1;
+        state.token_latency.observe(latency);
+    }
+
+    fn stream_opened(&self, method: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state.streams.entry(method.to_owned()).or_default() += 1;
+    }
+
+    fn stream_closed(&self, method: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state.streams.entry(method.to_owned()).or_default() -= 1;
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_the_prometheus_text_format() {
+        let metrics = InMemoryMetrics::new();
+        metrics.rpc_finished("GetDocument", "Ok", Duration::from_millis(20));
+        metrics.rpc_finished("GetDocument", "Ok", Duration::from_secs(20));
+        metrics.rpc_retried("GetDocument", "Unavailable");
+        metrics.token_fetched(false, Duration::from_millis(3));
+        metrics.stream_opened("RunQuery");
+        metrics.stream_opened("RunQuery");
+        metrics.stream_closed("RunQuery");
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE firestore_rpc_total counter\n"));
+        assert!(text.contains("firestore_rpc_total{method=\"GetDocument\",code=\"Ok\"} 2\n"));
+        assert!(text.contains(
+            "firestore_rpc_latency_seconds_bucket{method=\"GetDocument\",le=\"0.025\"} 1\n"
+        ));
+        assert!(text.contains(
+            "firestore_rpc_latency_seconds_bucket{method=\"GetDocument\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(text.contains(
+            "firestore_rpc_retries_total{method=\"GetDocument\",code=\"Unavailable\"} 1\n"
+        ));
+        assert!(text.contains("firestore_streams_in_flight{method=\"RunQuery\"} 1\n"));
+        assert!(text.contains("google_token_fetches_total{outcome=\"error\"} 1\n"));
+        assert!(text.contains("google_token_fetch_latency_seconds_count 1\n"));
+    }
+}
@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::error::Error;
+
+/// Whether repeating a call can change its outcome. Reads and deletes are safe to repeat;
+/// creates and commits are not, since the first attempt may have landed before it failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Idempotency {
+    Idempotent,
+    NonIdempotent,
+}
+
+/// How failed calls are retried. Calls fail for good once `max_attempts` have been made, or
+/// when waiting for the next attempt would run past `deadline`.
+///
+/// Only idempotent calls are retried, on `UNAVAILABLE`, `DEADLINE_EXCEEDED` and
+/// `RESOURCE_EXHAUSTED`. Set `retry_non_idempotent` when writes are made idempotent, e.g.
+/// by client-chosen document ids and update preconditions.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// The fraction of each backoff that is randomized, between 0 and 1.
+    pub jitter: f64,
+    pub deadline: Option<Duration>,
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 1.3,
+            jitter: 0.2,
+            deadline: Some(Duration::from_secs(60)),
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Makes every call exactly once.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// How long to wait before attempt `attempt + 1` after `error`, having spent `elapsed`
+    /// so far, or `None` to give up. A delay from the server's `RetryInfo` wins over the
+    /// backoff.
+    pub fn delay(
+        &self,
+        idempotency: Idempotency,
+        attempt: u32,
+        elapsed: Duration,
+        error: &Error,
+    ) -> Option<Duration> {
+        let retries = idempotency == Idempotency::Idempotent || self.retry_non_idempotent;
+        if attempt >= self.max_attempts || !retries || !error.is_retryable() {
+            return None;
+        }
+        let delay = error.retry_delay().unwrap_or_else(|| self.backoff(attempt));
+        match self.deadline {
+            Some(deadline) if elapsed + delay >= deadline => None,
+            _ => Some(delay),
+        }
+    }
+
+    /// The jittered backoff after `attempt` failed attempts.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1) as i32;
+        let backoff = self
+            .initial_backoff
+            .mul_f64(self.multiplier.max(1.0).powi(exponent))
+            .min(self.max_backoff);
+        let jitter = self.jitter.max(0.0).min(1.0);
+        backoff.mul_f64(1.0 - jitter * rand::thread_rng().gen::<f64>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::{Code, Status};
+
+    #[test]
+    fn it_backs_off_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3),
+            multiplier: 2.0,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3), Duration::from_secs(3));
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        let backoff = jittered.backoff(2);
+        assert!(backoff >= Duration::from_secs(1) && backoff <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn it_only_retries_transient_failures_of_idempotent_calls() {
+        let policy = RetryPolicy::default();
+        let unavailable = Error::from(Status::new(Code::Unavailable, "try again"));
+        let not_found = Error::from(Status::new(Code::NotFound, "gone"));
+        let no_time = Duration::from_secs(0);
+
+        assert!(policy
+            .delay(Idempotency::Idempotent, 1, no_time, &unavailable)
+            .is_some());
+        assert!(policy
+            .delay(Idempotency::Idempotent, 1, no_time, &not_found)
+            .is_none());
+        assert!(policy
+            .delay(Idempotency::NonIdempotent, 1, no_time, &unavailable)
+            .is_none());
+        assert!(policy
+            .delay(Idempotency::Idempotent, 5, no_time, &unavailable)
+            .is_none());
+        assert!(policy
+            .delay(
+                Idempotency::Idempotent,
+                1,
+                Duration::from_secs(60),
+                &unavailable
+            )
+            .is_none());
+    }
+}
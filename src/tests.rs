@@ -1 +1,3 @@
 mod firestore;
+#[cfg(test)]
+pub(crate) mod stub_server;
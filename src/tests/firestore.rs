@@ -1,14 +1,14 @@
 #[cfg(test)]
 mod firestore {
     use crate::connection::Credentials;
+    use crate::error::Error;
     use crate::firestore::v1::{Document, Firestore};
     use crate::google::firestore::v1::value::ValueType;
     use crate::google::firestore::v1::{
         CreateDocumentRequest, DeleteDocumentRequest, GetDocumentRequest,
     };
-    use std::error::Error;
 
-    async fn establish_connection() -> Result<Firestore, Box<dyn Error>> {
+    async fn establish_connection() -> Result<Firestore, Error> {
         Credentials::from_json(include_str!("./credentials.json"))
             .map(|c| Firestore::connect(c))
             .expect("Unable to create connection")
@@ -25,7 +25,7 @@ mod firestore {
 
     #[tokio::test]
     async fn it_creates_a_document() {
-        let mut connection = establish_connection()
+        let connection = establish_connection()
             .await
             .expect("Unable to establish connection");
 
@@ -45,7 +45,7 @@ mod firestore {
 
     #[tokio::test]
     async fn it_can_get_a_document() {
-        let mut connection = establish_connection()
+        let connection = establish_connection()
             .await
             .expect("Unable to establish connection");
 
@@ -61,7 +61,7 @@ mod firestore {
 
     #[tokio::test]
     async fn it_updates_a_document() {
-        let mut connection = establish_connection()
+        let connection = establish_connection()
             .await
             .expect("Unable to establish connection");
         let mut document = connection.new_document("awdagfawegac-doc");
@@ -91,7 +91,7 @@ mod firestore {
     }
 
     async fn test_create_read_delete() {
-        let mut connection = establish_connection()
+        let connection = establish_connection()
             .await
             .expect("Unable to establish connection");
 
@@ -130,4 +130,32 @@ mod firestore {
 
     #[tokio::test]
     async fn test_update() {}
+
+    #[tokio::test]
+    async fn it_talks_to_the_emulator() {
+        // Only runs when an emulator is available, e.g. `gcloud beta emulators firestore start`.
+        if std::env::var("FIRESTORE_EMULATOR_HOST").is_err() {
+            return;
+        }
+        let connection = Firestore::builder()
+            .project_id("emulator-project")
+            .connect()
+            .await
+            .expect("Unable to connect to the emulator");
+
+        let mut document = connection.new_document("emulated-doc");
+        document.push_address("test-collection");
+        let created = connection
+            .create_document(document.create_document_request())
+            .await;
+        debug_assert!(created.is_ok(), "{:?}", &created);
+
+        let fetched = connection.get_document(document.get_document_request()).await;
+        debug_assert!(fetched.is_ok(), "{:?}", &fetched);
+
+        let deleted = connection
+            .delete_document(document.delete_document_request())
+            .await;
+        debug_assert!(deleted.is_ok(), "{:?}", &deleted);
+    }
 }
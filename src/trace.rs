@@ -0,0 +1,96 @@
+use rand::Rng;
+use tonic::metadata::{MetadataMap, MetadataValue};
+
+/// The distributed trace a call belongs to, propagated to Google as `traceparent` and
+/// `x-cloud-trace-context` so the server's spans join the caller's trace.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    /// The caller's span, which the outgoing call is a child of.
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Parses a W3C `traceparent` header, e.g. one received by the calling service.
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        match parts.as_slice() {
+            ["00", trace_id, span_id, flags]
+                if trace_id.len() == 32 && span_id.len() == 16 && flags.len() == 2 =>
+            {
+                Some(TraceContext {
+                    trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+                    span_id: u64::from_str_radix(span_id, 16).ok()?,
+                    sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// A new span in the same trace, for one outgoing call.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            span_id: rand::thread_rng().gen_range(1, u64::MAX),
+            ..*self
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+
+    pub fn cloud_trace_context(&self) -> String {
+        format!(
+            "{:032x}/{};o={}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+
+    /// Adds both headers, unless the caller already set trace headers on the request.
+    pub(crate) fn inject(&self, metadata: &mut MetadataMap) {
+        if metadata.get("traceparent").is_some() || metadata.get("x-cloud-trace-context").is_some()
+        {
+            return;
+        }
+        let headers = [
+            ("traceparent", self.traceparent()),
+            ("x-cloud-trace-context", self.cloud_trace_context()),
+        ];
+        for (name, value) in headers.iter() {
+            if let Ok(value) = MetadataValue::from_str(value) {
+                metadata.insert(*name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_formats_both_header_flavours() {
+        let context = TraceContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap();
+        assert_eq!(
+            context.traceparent(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+        assert_eq!(
+            context.cloud_trace_context(),
+            "4bf92f3577b34da6a3ce929d0e0e4736/67667974448284343;o=1"
+        );
+
+        let child = context.child();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_ne!(child.span_id, context.span_id);
+        assert_eq!(TraceContext::from_traceparent("garbage"), None);
+    }
+}